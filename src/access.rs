@@ -0,0 +1,162 @@
+use crate::request::{self, Operation};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Role of a user, deciding which operations the user may perform at all.
+/// Which keys a writer or reader may touch is still decided by the ACL.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Role {
+    // Grants permissions, appoints roles, and may read and write every key
+    Admin,
+    // Reads and writes the keys they are permitted on, the role of newly registered users
+    Writer,
+    // Only reads the keys they are permitted on
+    Reader,
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "admin" => Some(Role::Admin),
+            "writer" => Some(Role::Writer),
+            "reader" => Some(Role::Reader),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Writer => "writer",
+            Role::Reader => "reader",
+        }
+    }
+}
+
+/// What a user may do with a key, as a set of flags
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Permissions(u8);
+
+impl Permissions {
+    pub const READ: Self = Permissions(1);
+    pub const WRITE: Self = Permissions(2);
+    pub const DELETE: Self = Permissions(4);
+    pub const GRANT: Self = Permissions(8);
+    pub const ALL: Self = Permissions(15);
+
+    const NAMES: [(&'static str, Permissions); 4] = [
+        ("read", Self::READ),
+        ("write", Self::WRITE),
+        ("delete", Self::DELETE),
+        ("grant", Self::GRANT),
+    ];
+
+    /// Parse a comma separated list of permissions such as `read,write`, or `all`
+    pub fn parse(permissions: &str) -> Option<Self> {
+        if permissions == "all" {
+            return Some(Self::ALL);
+        }
+        permissions.split(',').try_fold(Permissions::default(), |parsed, name| {
+            let (_, permission) = Self::NAMES.iter().find(|(known, _)| *known == name)?;
+            Some(Permissions(parsed.0 | permission.0))
+        })
+    }
+
+    pub fn contains(&self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The permission needed on a key to perform `op` on it
+    pub fn required_for(op: Operation) -> Self {
+        match op {
+            Operation::Get | Operation::GetFile => Self::READ,
+            Operation::Put | Operation::PutFile | Operation::Recipients => Self::WRITE,
+            Operation::Delete => Self::DELETE,
+            Operation::Grant => Self::GRANT,
+            // Only the owner or an admin may revoke and list permissions on a key,
+            // roles, groups and the audit log are only for admins and keys only rotated by their user
+            Operation::Revoke
+            | Operation::ListAcl
+            | Operation::ListUserAcl
+            | Operation::SetRole
+            | Operation::GroupAdd
+            | Operation::GroupRemove
+            | Operation::RotateKey
+            | Operation::Register
+            | Operation::Audit => Self::ALL,
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn union(&self, other: Permissions) -> Self {
+        Permissions(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(_, permission)| self.contains(*permission))
+            .map(|(name, _)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+const GROUP_PREFIX: &str = "group:";
+
+/// Who an ACL entry applies to: a single user, or every member of a group
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Subject {
+    User(Vec<u8>),
+    Group(String),
+}
+
+impl Subject {
+    /// Parse `group:<name>`, or else a user's hex public key
+    pub fn parse(subject: &str) -> Option<Self> {
+        match subject.strip_prefix(GROUP_PREFIX) {
+            Some(group) => is_valid_group_name(group).then(|| Subject::Group(group.to_string())),
+            None => hex::decode(subject).ok().map(Subject::User),
+        }
+    }
+}
+
+// Also the form subjects are stored in the ACL and in grant record keys
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::User(public_key) => write!(f, "{}", hex::encode(public_key)),
+            Subject::Group(group) => write!(f, "{}{}", GROUP_PREFIX, group),
+        }
+    }
+}
+
+/// Group names end up in record keys, so they may not contain a '/'
+pub fn is_valid_group_name(group: &str) -> bool {
+    !group.is_empty() && !group.contains('/')
+}
+
+/// Hash a `grant` request signs: the user or group and the permissions given
+pub fn grant_content_hash(subject: &Subject, permissions: Permissions) -> [u8; 32] {
+    let mut content = subject.to_string().into_bytes();
+    content.push(permissions.bits());
+    request::content_hash(&content)
+}
+
+/// Hash a `revoke` request signs: the user or group losing its permissions
+pub fn revoke_content_hash(subject: &Subject) -> [u8; 32] {
+    request::content_hash(subject.to_string().as_bytes())
+}
@@ -0,0 +1,88 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Address `kv-client` talks to when no `--node` is given
+pub const DEFAULT_API_ADDR: &str = "127.0.0.1:7400";
+
+/// Largest message accepted on the client API, enough for the chunks of a stored file
+pub const MAX_FRAME_LEN: usize = 512 * 1024 * 1024;
+
+/// A request from `kv-client` to a node. Everything the node acts on is signed by
+/// the client beforehand, the node never sees a private key.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ApiRequest {
+    // A new user, with the user's `register` request for the username and public key
+    Register { username: String, public_key: Vec<u8>, signature: Vec<u8>, admin: bool },
+    Put { key: String, value: Vec<u8>, public_key: Vec<u8>, signature: Vec<u8> },
+    Get { key: String, public_key: Vec<u8>, signature: Vec<u8> },
+    // Who the client is to wrap the file key of an encrypted file for, answered with Keys
+    Recipients { key: String, public_key: Vec<u8>, signature: Vec<u8> },
+    // A file split, and encrypted if need be, by the client: the encoded manifest, whose hash
+    // is what the put-file request signs, and the chunks it lists, in its order
+    PutFile { key: String, manifest: Vec<u8>, chunks: Vec<Vec<u8>>, public_key: Vec<u8>, signature: Vec<u8> },
+    // The encoded manifest of a file, for the client to fetch and decrypt the chunks itself (signed as get-file)
+    GetFileManifest { key: String, public_key: Vec<u8>, signature: Vec<u8> },
+    // A chunk, or erasure coded shard, by the hash it is stored under. Chunks are only found
+    // through a manifest and are checked against their hash, so this is not signed.
+    // `attempt` counts the tries so far, retries ask more peers for a copy.
+    GetChunk { hash: [u8; 32], shard: bool, attempt: u32 },
+    // Sequence number of the latest write stored under a key, the value or with `file` the
+    // file manifest, for the client to sign the next write with. Like a chunk it is not signed.
+    Sequence { key: String, file: bool },
+    Delete { key: String, public_key: Vec<u8>, signature: Vec<u8> },
+    // `subject` and `permissions` as typed on the command line
+    Grant { key: String, subject: String, permissions: String, public_key: Vec<u8>, signature: Vec<u8> },
+    Revoke { key: String, subject: String, public_key: Vec<u8>, signature: Vec<u8> },
+    Acl { key: String, public_key: Vec<u8>, signature: Vec<u8> },
+    UserAcl { user: Vec<u8>, public_key: Vec<u8>, signature: Vec<u8> },
+    SetRole { user: Vec<u8>, role: String, public_key: Vec<u8>, signature: Vec<u8> },
+    GroupMember { group: String, user: Vec<u8>, member: bool, public_key: Vec<u8>, signature: Vec<u8> },
+    // Replace the user's key pair: `signature` is the old key's rotate-key request and
+    // `new_signature` the new key's `register` request for the same username
+    RotateKey { public_key: Vec<u8>, signature: Vec<u8>, new_public_key: Vec<u8>, new_signature: Vec<u8> },
+    // The audit log, or only its entries for a key, for an admin
+    Audit { key: Option<String>, public_key: Vec<u8>, signature: Vec<u8> },
+    // Every user with their keys, or only the one named
+    Users { username: Option<String> },
+    // Progress of the downloads started on the node's prompt
+    Downloads,
+    // Which peers the node is connected to or saw lately
+    Peers,
+}
+
+/// A node's answer to an `ApiRequest`
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ApiResponse {
+    // The request was carried out, with a message for the user
+    Done(String),
+    // The value stored under a key
+    Value(Vec<u8>),
+    // ed25519 public keys, such as the recipients of a file key
+    Keys(Vec<Vec<u8>>),
+    // Lines of a listing such as an ACL
+    Lines(Vec<String>),
+    // Sequence number of the latest write to a key, 0 if it was never written
+    Sequence(u64),
+    Error(String),
+}
+
+/// Encode a message as sent on the client API: its length as 4 big-endian bytes, then bincode
+pub fn encode_frame<T: Serialize>(message: &T) -> Result<Vec<u8>, String> {
+    let body = bincode::serialize(message).map_err(|e| e.to_string())?;
+    if body.len() > MAX_FRAME_LEN {
+        return Err("Message too large".to_string());
+    }
+    let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+    frame.extend(body);
+    Ok(frame)
+}
+
+/// Length of the frame body announced by a frame header, None if it is too large
+pub fn frame_len(header: [u8; 4]) -> Option<usize> {
+    let len = u32::from_be_bytes(header) as usize;
+    (len <= MAX_FRAME_LEN).then_some(len)
+}
+
+pub fn decode_frame<T: DeserializeOwned>(body: &[u8]) -> Option<T> {
+    bincode::deserialize(body).ok()
+}
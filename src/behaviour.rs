@@ -1,43 +1,88 @@
 use libp2p::NetworkBehaviour;
 use libp2p::mdns::{Mdns, MdnsEvent};
+use futures::channel::oneshot;
 use libp2p::kad::{
-    record::store::MemoryStore,
+    record::{store::RecordStore, Key},
+    GetRecordError,
     Kademlia,
     KademliaEvent,
     QueryResult,
-    Record,
+    Quorum,
+    BootstrapOk,
     PeerRecord,
     PutRecordOk,
 };
-use libp2p::swarm::NetworkBehaviourEventProcess;
-use std::io::Write;
+use libp2p::swarm::{toggle::Toggle, NetworkBehaviourEventProcess};
+use crate::download::{Download, DownloadOutcome};
+use crate::file::{chunk_hash, FileManifest};
+use crate::keys::RecordKey;
+use crate::peers::PeerLiveness;
+use crate::node::auth::SignedWrite;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
 
+/// Where to send a retrieved record, or why it could not be retrieved
+pub type RecordWaiter = oneshot::Sender<Result<Vec<u8>, String>>;
+
+/// Where to send the sequence number of the latest write found under a key
+pub type SequenceWaiter = oneshot::Sender<u64>;
+
+// Generic over the record store so the node can pick where records live
+// (see node/store.rs for the on-disk store).
 #[derive(NetworkBehaviour)]
-pub struct Behaviour {
+pub struct Behaviour<TStore: 'static> {
     // From mDNS: Discovers peers and adds them to Kademlia.
-    pub kademlia: Kademlia<MemoryStore>,
+    pub kademlia: Kademlia<TStore>,
     // From Kademlia: Logs key-value operations, such as retrieval or storage success/failure.
-    pub mdns: Mdns,
+    // Turned off with --no-mdns, see Node::dial for finding peers without it
+    pub mdns: Toggle<Mdns>,
+    // Files being downloaded, by file key
+    #[behaviour(ignore)]
+    pub downloads: HashMap<String, Download>,
+    // DHT key of a chunk or shard -> (file key, chunk index) of every download waiting for it
+    #[behaviour(ignore)]
+    pub pending_chunks: HashMap<Key, Vec<(String, usize)>>,
+    // How many chunks of a download are fetched at the same time
+    #[behaviour(ignore)]
+    pub download_window: usize,
+    // How each finished download ended, by file key
+    #[behaviour(ignore)]
+    pub download_outcomes: HashMap<String, DownloadOutcome>,
+    // Clients waiting for the value, manifest or chunk stored under a key (see server.rs)
+    #[behaviour(ignore)]
+    pub record_waiters: HashMap<Key, Vec<RecordWaiter>>,
+    // Clients waiting for the sequence number of the value or manifest stored under a key
+    #[behaviour(ignore)]
+    pub sequence_waiters: HashMap<Key, Vec<SequenceWaiter>>,
+    // Which peers are connected, were seen lately or keep failing to answer dials
+    #[behaviour(ignore)]
+    pub peers: PeerLiveness,
 }
 
 // Handle mDNS events: Triggered when new peers are found on the local network.
-impl NetworkBehaviourEventProcess<MdnsEvent> for Behaviour {
+impl<TStore> NetworkBehaviourEventProcess<MdnsEvent> for Behaviour<TStore>
+where
+    for<'a> TStore: RecordStore<'a> + Send + 'static,
+{
     fn inject_event(&mut self, event: MdnsEvent) {
-        /*
-        1. check if event is MdnsEvent::Discovered
-        2. if True, destructure it to extract list and execute the block {}
-        if let MdnsEvent::Discovered(list) = event: 
-            checks if the value of event matches the variant MdnsEvent::Discovered
-        If event is of type MdnsEvent::Discovered, MdnsEvent is an enum,
-            DiscoveredList(list) is one possible variants 
-        then the block inside {} executes
-            the variable list becomes available 
-        */
-        if let MdnsEvent::Discovered(list) = event {
-            for (peer_id, multiaddr) in list {
-                //  Adds the discovered peer’s address to the 
-                // Kademlia node for further communication.
-                self.kademlia.add_address(&peer_id, multiaddr);
+        match event {
+            MdnsEvent::Discovered(list) => {
+                for (peer_id, multiaddr) in list {
+                    // Adds the discovered peer’s address to the
+                    // Kademlia node for further communication.
+                    self.peers.discovered(peer_id);
+                    self.kademlia.add_address(&peer_id, multiaddr);
+                }
+            }
+            MdnsEvent::Expired(list) => {
+                for (peer_id, multiaddr) in list {
+                    // The peer stopped announcing the address. Kademlia forgets the peer along
+                    // with its last address, unless a connection to it is still open.
+                    if !self.peers.is_connected(&peer_id) {
+                        self.kademlia.remove_address(&peer_id, &multiaddr);
+                    }
+                }
             }
         }
     }
@@ -45,138 +90,127 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for Behaviour {
 
 // Handle Kademlia events
 // https://tidelabs.github.io/tidechain/src/libp2p_kad/behaviour.rs.html#2479
-impl NetworkBehaviourEventProcess<KademliaEvent> for Behaviour {
+impl<TStore> NetworkBehaviourEventProcess<KademliaEvent> for Behaviour<TStore>
+where
+    for<'a> TStore: RecordStore<'a> + Send + 'static,
+{
     fn inject_event(&mut self, event: KademliaEvent) {
-        match event {
-            KademliaEvent::OutboundQueryCompleted { result, .. } => match result {
+        if let KademliaEvent::OutboundQueryCompleted { result, .. } = event {
+            match result {
                 QueryResult::GetRecord(Ok(ok)) => {
-                    for PeerRecord { record: Record { key, value, .. }, .. } in ok.records {
-                        let key = String::from_utf8_lossy(key.as_ref()).to_string();
-                        let value = value.clone();
-                        // Check if the key is a traditional key-value pair or part of a file storage system
-                        if !key.contains('_') {
-                            // Case 1: Traditional key-value pair
-                            let value_str = String::from_utf8_lossy(&value);
-                            println!(
-                                "Retrieved traditional key-value pair: Key = '{}', Value = '{}'",
-                                key, value_str
-                            );
-                            continue; // Skip further processing for this key
+                    // All records returned by a query share the queried key
+                    let query_key = match ok.records.first().and_then(|r| RecordKey::parse(&r.record.key)) {
+                        Some(key) => key,
+                        None => {
+                            eprintln!("Retrieved a record with an unexpected key format");
+                            return;
                         }
+                    };
 
-                        let parts: Vec<&str> = key.split('_').collect();
-                        if parts.len() == 2 {
-                            // Case 2: Key contains 1 underscore, this represents total chunk metadata
-                            let file_key = parts[0];
-                            let total_chunk_number: usize = String::from_utf8_lossy(&value)
-                                .parse()
-                                .unwrap_or_else(|_| {
-                                    eprintln!("Invalid total chunk number in key '{}'", key);
-                                    0
-                                });
-
-                            if total_chunk_number == 0 {
-                                eprintln!("Total chunk number is 0 for key '{}'.", key);
-                                continue;
-                            }
-
-                            // Retrieve the first chunk
-                            let first_chunk_key = libp2p::kad::record::Key::new(&format!(
-                                "{}_{}_{}",
-                                file_key, 0, total_chunk_number
-                            ));
-                            self.kademlia
-                                .get_record(&first_chunk_key, libp2p::kad::Quorum::One);
-                            println!(
-                                "Retrieving first chunk for file '{}', total chunks: {}.",
-                                file_key, total_chunk_number
-                            );
-                        } else if parts.len() == 3 {
-                            // Case 3: Key contains 2 underscores, this represents a file chunk
-                            // Parse the file key, current chunk number, and total chunk number
-                            let file_key = parts[0];
-                            let current_chunk_number: usize = parts[1].parse().unwrap_or_else(|_| {
-                                eprintln!("Invalid current chunk number in key '{}'", key);
-                                0
-                            });
-                            let total_chunk_number: usize = parts[2].parse().unwrap_or_else(|_| {
-                                eprintln!("Invalid total chunk number in key '{}'", key);
-                                0
-                            });
-
-                            // Write the current chunk to disk
-                            let file_path = std::env::current_dir()
-                                .expect("Failed to get current directory")
-                                .join(format!("{}.txt", file_key));
-
-                            let mut open_options = std::fs::OpenOptions::new();
-                            if current_chunk_number == 0 {
-                                // Overwrite the file if it's the first chunk
-                                open_options.create(true).write(true).truncate(true);
-                            } else {
-                                // Append to the file for other chunks
-                                open_options.create(true).append(true);
-                            }
+                    self.answer_sequence_waiters(&query_key, &ok.records);
 
-                            match open_options.open(&file_path) {
-                                Ok(mut file) => {
-                                    if let Err(e) = file.write_all(&value) {
-                                        eprintln!(
-                                            "Failed to write chunk {} to file '{}': {:?}",
-                                            current_chunk_number, file_path.display(), e
-                                        );
-                                    } else {
-                                        println!(
-                                            "Successfully wrote chunk {} to file '{}'.",
-                                            current_chunk_number, file_path.display()
-                                        );
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to open file '{}': {:?}", file_path.display(), e);
+                    match query_key {
+                        RecordKey::Value(ref key) => {
+                            // Case 1: Traditional key-value pair.
+                            // Copies whose writer did not sign them are left out, the latest write comes first.
+                            let mut writes: Vec<SignedWrite> = ok
+                                .records
+                                .iter()
+                                .filter_map(|r| SignedWrite::decode(&r.record.value))
+                                .filter(|write| write.verify(&query_key))
+                                .collect();
+                            writes.sort_by_key(|write| std::cmp::Reverse(write.seq()));
+                            let values: Vec<Vec<u8>> = writes.into_iter().map(|write| write.value).collect();
+                            let record_key = query_key.to_key();
+                            match values.first() {
+                                Some(value) => self.answer_waiters(&record_key, Ok(value.clone())),
+                                None => {
+                                    eprintln!("No validly signed value for key '{}'", key);
+                                    self.answer_waiters(&record_key, Err("no validly signed value".to_string()));
                                 }
                             }
-
-                            // Check if we have reached the last chunk
-                            if current_chunk_number + 1 == total_chunk_number {
+                            for value in values {
+                                let value_str = String::from_utf8_lossy(&value);
                                 println!(
-                                    "All chunks retrieved for file '{}'. File written to '{}'.",
-                                    file_key, file_path.display()
-                                );
-                            } else {
-                                // Retrieve the next chunk
-                                let next_chunk_key = libp2p::kad::record::Key::new(&format!(
-                                    "{}_{}_{}",
-                                    file_key,
-                                    current_chunk_number + 1,
-                                    total_chunk_number
-                                ));
-                                self.kademlia
-                                    .get_record(&next_chunk_key, libp2p::kad::Quorum::One);
-                                println!(
-                                    "Retrieving next chunk: {} for file '{}'",
-                                    current_chunk_number + 1,
-                                    file_key
+                                    "Retrieved traditional key-value pair: Key = '{}', Value = '{}'",
+                                    key, value_str
                                 );
                             }
-                        } else {
-                            eprintln!("Unexpected key format: '{}'", key);
                         }
-
-
-
-                        
+                        RecordKey::Manifest(ref file_key) => {
+                            // Case 2: the manifest of a file, start fetching its chunks.
+                            // The validly signed copy of the latest write is used.
+                            let manifest = ok
+                                .records
+                                .iter()
+                                .filter_map(|r| {
+                                    let write = SignedWrite::decode(&r.record.value)
+                                        .filter(|write| write.verify(&query_key))?;
+                                    FileManifest::decode(&write.value).map(|manifest| (write.seq(), &r.record, write.value, manifest))
+                                })
+                                .max_by_key(|(sequence, ..)| *sequence)
+                                .map(|(_, record, value, manifest)| (record, value, manifest));
+                            match manifest {
+                                // A client only asked for the manifest, to fetch the file itself
+                                Some((record, value, _)) if self.record_waiters.contains_key(&record.key) => {
+                                    self.answer_waiters(&record.key, Ok(value));
+                                }
+                                Some((_, _, manifest)) => self.start_download(file_key, manifest),
+                                None => {
+                                    eprintln!("Invalid file manifest for file '{}'", file_key);
+                                    self.answer_waiters(&query_key.to_key(), Err("no validly signed file manifest".to_string()));
+                                }
+                            }
+                        }
+                        RecordKey::Chunk(hash) | RecordKey::Shard(hash) => {
+                            // Case 3: a file chunk, or a shard of an erasure coded file.
+                            // A client fetching it is handed the first copy that matches the hash.
+                            let chunk_key = query_key.to_key();
+                            let chunk = ok
+                                .records
+                                .iter()
+                                .find(|r| chunk_hash(&r.record.value) == hash)
+                                .map(|r| r.record.value.clone());
+                            self.answer_waiters(&chunk_key, chunk.ok_or_else(|| "no copy matching the chunk hash".to_string()));
+                            self.on_chunk_records(&chunk_key, hash, &ok.records);
+                        }
+                        RecordKey::User(_) | RecordKey::Grant { .. } | RecordKey::Owner(_) | RecordKey::Member { .. } => {
+                            // Case 4: a user, permission, owner or group record a request needed
+                            // (see Node::look_up_access_records). Storing it runs the validator,
+                            // which verifies it and takes it into account.
+                            for PeerRecord { record, .. } in &ok.records {
+                                if let Err(e) = self.kademlia.store_mut().put(record.clone()) {
+                                    eprintln!("Refused {}: {:?}", query_key, e);
+                                }
+                            }
+                            self.answer_waiters(&query_key.to_key(), Ok(Vec::new()));
+                        }
                     }
                 }
 
                 QueryResult::PutRecord(Ok(PutRecordOk { key })) => {
-                    println!(
-                        "Successfully added record with key: '{}'",
-                        String::from_utf8_lossy(key.as_ref())
-                    );
+                    match RecordKey::parse(&key) {
+                        Some(key) => println!("Successfully added record: {}", key),
+                        None => println!(
+                            "Successfully added record with key: '{}'",
+                            String::from_utf8_lossy(key.as_ref())
+                        ),
+                    }
                 }
 
                 QueryResult::GetRecord(Err(err)) => {
+                    // Copies may still have come back from fewer peers than asked for
+                    let records = match &err {
+                        GetRecordError::QuorumFailed { records, .. } => records.as_slice(),
+                        GetRecordError::Timeout { records, .. } => records.as_slice(),
+                        GetRecordError::NotFound { .. } => &[],
+                    };
+                    match RecordKey::parse(err.key()) {
+                        Some(RecordKey::Chunk(hash) | RecordKey::Shard(hash)) => self.on_chunk_records(err.key(), hash, records),
+                        Some(query_key) => self.answer_sequence_waiters(&query_key, records),
+                        None => {}
+                    }
+                    self.answer_waiters(err.key(), Err("record not found".to_string()));
                     eprintln!("Failed to retrieve record: {:?}", err);
                 }
 
@@ -184,10 +218,254 @@ impl NetworkBehaviourEventProcess<KademliaEvent> for Behaviour {
                     eprintln!("Failed to add record: {:?}", err);
                 }
 
+                QueryResult::Bootstrap(Ok(BootstrapOk { num_remaining: 0, .. })) => {
+                    let peers: usize = self.kademlia.kbuckets().map(|bucket| bucket.num_entries()).sum();
+                    println!("Bootstrap complete, {} peers in the routing table", peers);
+                }
+
+                QueryResult::Bootstrap(Err(err)) => {
+                    eprintln!("Bootstrap failed: {:?}", err);
+                }
+
                 _ => {}
-            },
-            _ => {}
+            }
         }
     }
 }
 
+impl<TStore> Behaviour<TStore>
+where
+    for<'a> TStore: RecordStore<'a> + Send + 'static,
+{
+    // Hand a retrieved record (or why it could not be retrieved) to the clients waiting for it
+    fn answer_waiters(&mut self, key: &Key, result: Result<Vec<u8>, String>) {
+        for waiter in self.record_waiters.remove(key).unwrap_or_default() {
+            // The client may have gone away in the meantime
+            let _ = waiter.send(result.clone());
+        }
+    }
+
+    // Tell the clients waiting for the sequence number of a value or manifest the highest one
+    // among the validly signed copies found, 0 if there are none
+    fn answer_sequence_waiters(&mut self, query_key: &RecordKey, records: &[PeerRecord]) {
+        if !matches!(query_key, RecordKey::Value(_) | RecordKey::Manifest(_)) {
+            return;
+        }
+        let seq = records
+            .iter()
+            .filter_map(|r| SignedWrite::decode(&r.record.value))
+            .filter(|write| write.verify(query_key))
+            .map(|write| write.seq())
+            .max()
+            .unwrap_or(0);
+        for waiter in self.sequence_waiters.remove(&query_key.to_key()).unwrap_or_default() {
+            // The client may have gone away in the meantime
+            let _ = waiter.send(seq);
+        }
+    }
+
+    /// Begin downloading a file once its manifest has been retrieved
+    fn start_download(&mut self, file_key: &str, manifest: FileManifest) {
+        if self.downloads.contains_key(file_key) {
+            println!("Download of '{}' is already in progress.", file_key);
+            return;
+        }
+
+        // Encrypted files can only be read by users the file key was wrapped for, whose
+        // client fetches and decrypts them itself
+        if manifest.wrapped_keys.is_some() {
+            let outcome = DownloadOutcome::Failed(
+                "cannot decrypt file: the file is encrypted, retrieve it with kv-client get-file".to_string(),
+            );
+            println!("Download of '{}' {}", file_key, outcome);
+            self.download_outcomes.insert(file_key.to_string(), outcome);
+            return;
+        }
+
+        let file_path = std::env::current_dir()
+            .expect("Failed to get current directory")
+            .join(manifest.local_file_name(file_key));
+
+        // Picks up a previous partial download of the same file if there is one
+        let download = match Download::open(manifest, file_path, None) {
+            Ok(download) => download,
+            Err(e) => {
+                eprintln!("Failed to create file for '{}': {:?}", file_key, e);
+                return;
+            }
+        };
+
+        println!(
+            "Retrieving file '{}', total chunks: {}, up to {} at a time.",
+            file_key,
+            download.manifest.chunks.len(),
+            self.download_window
+        );
+        self.downloads.insert(file_key.to_string(), download);
+        self.download_outcomes.remove(file_key);
+        self.fill_window(file_key);
+    }
+
+    /// Request chunks until the download has a full window of them in flight,
+    /// or finish it once every chunk is on disk
+    fn fill_window(&mut self, file_key: &str) {
+        let download = match self.downloads.get_mut(file_key) {
+            Some(download) => download,
+            None => return,
+        };
+        if download.is_complete() {
+            self.finish_download(file_key);
+            return;
+        }
+        for index in download.next_requests(self.download_window) {
+            let attempts = download.attempts(index);
+            let chunk_key = download.manifest.chunk_key(index).to_key();
+
+            let waiting = self.pending_chunks.entry(chunk_key.clone()).or_default();
+            waiting.push((file_key.to_string(), index));
+            // The same content may already have been asked for, by this file or another one
+            if waiting.len() > 1 {
+                continue;
+            }
+            // Retries ask for more copies so that peers other than the one that failed get a say
+            let quorum = match NonZeroUsize::new(attempts.min(3) as usize) {
+                Some(n) if n.get() > 1 => Quorum::N(n),
+                _ => Quorum::One,
+            };
+            self.kademlia.get_record(&chunk_key, quorum);
+        }
+    }
+
+    /// Hand the copies of a chunk returned by a query to every download waiting for it.
+    /// Only a copy whose content matches the hash is accepted, peers returning anything
+    /// else are not trusted for the rest of the download.
+    fn on_chunk_records(&mut self, key: &Key, hash: [u8; 32], records: &[PeerRecord]) {
+        for (file_key, index) in self.pending_chunks.remove(key).unwrap_or_default() {
+            let download = match self.downloads.get_mut(&file_key) {
+                Some(download) => download,
+                None => continue,
+            };
+            let mut chunk = None;
+            for PeerRecord { peer, record } in records {
+                if peer.is_some_and(|peer| download.is_bad_peer(&peer)) {
+                    continue;
+                }
+                if chunk_hash(&record.value) == hash {
+                    chunk = Some(record.value.as_slice());
+                    break;
+                }
+                if let Some(peer) = peer {
+                    eprintln!("Peer {} returned a corrupted copy of chunk {} of '{}'.", peer, index, file_key);
+                    download.mark_bad_peer(*peer);
+                }
+            }
+            self.on_chunk(&file_key, index, chunk);
+        }
+    }
+
+    /// Handle the outcome of fetching a chunk. `chunk` is `None` if the chunk could not
+    /// be retrieved or none of the returned copies matched its hash.
+    fn on_chunk(&mut self, file_key: &str, index: usize, chunk: Option<&[u8]>) {
+        let download = match self.downloads.get_mut(file_key) {
+            Some(download) => download,
+            None => return,
+        };
+        if !download.is_in_flight(index) {
+            return;
+        }
+
+        match chunk {
+            Some(chunk) => {
+                // Write the verified chunk at its place in the file, or keep it until its stripe can be rebuilt
+                if let Err(e) = download.chunk_received(index, chunk) {
+                    let reason = format!("could not write chunk {}: {:?}", index, e);
+                    self.fail_download(file_key, reason);
+                    return;
+                }
+                println!(
+                    "Successfully retrieved chunk {} of file '{}' ({}/{} parts written).",
+                    index,
+                    download.file_path.display(),
+                    download.completed(),
+                    download.total()
+                );
+            }
+            None => {
+                let attempts = download.attempts(index);
+                if !download.chunk_failed(index) {
+                    let reason = format!("chunk {} could not be retrieved after {} attempts", index, attempts);
+                    self.fail_download(file_key, reason);
+                    return;
+                }
+                eprintln!(
+                    "Chunk {} of file '{}' is missing or corrupted, retrying (attempt {} failed).",
+                    index, file_key, attempts
+                );
+            }
+        }
+
+        self.fill_window(file_key);
+    }
+
+    fn finish_download(&mut self, file_key: &str) {
+        let download = match self.downloads.remove(file_key) {
+            Some(download) => download,
+            None => return,
+        };
+        // Make sure nothing was lost or duplicated on the way
+        let outcome = match download.finish() {
+            Ok(()) => DownloadOutcome::Completed(download.file_path.clone()),
+            Err(e) => DownloadOutcome::Failed(format!("verification failed: {}", e)),
+        };
+        println!("Download of '{}' {}", file_key, outcome);
+        self.download_outcomes.insert(file_key.to_string(), outcome);
+    }
+
+    /// Give up on a download. Its progress file is kept so a later `get -f` resumes it.
+    fn fail_download(&mut self, file_key: &str, reason: String) {
+        self.downloads.remove(file_key);
+        self.pending_chunks.retain(|_, waiting| {
+            waiting.retain(|(key, _)| key != file_key);
+            !waiting.is_empty()
+        });
+        let outcome = DownloadOutcome::Failed(reason);
+        eprintln!("Download of '{}' {}", file_key, outcome);
+        self.download_outcomes.insert(file_key.to_string(), outcome);
+    }
+
+    /// Request again the chunks whose backoff is over
+    pub fn retry_due_chunks(&mut self) {
+        let file_keys: Vec<String> = self.downloads.keys().cloned().collect();
+        for file_key in file_keys {
+            self.fill_window(&file_key);
+        }
+    }
+
+    /// Time until the next failed chunk is due to be retried, if any
+    pub fn next_retry_in(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.downloads
+            .values()
+            .filter_map(|download| download.next_retry_at())
+            .min()
+            .map(|retry_at| retry_at.saturating_duration_since(now))
+    }
+
+    /// One line per download in progress or finished, as shown by the downloads command
+    pub fn download_status(&self) -> Vec<String> {
+        let in_progress = self.downloads.iter().map(|(file_key, download)| {
+            format!(
+                "{}: {}/{} parts done, {} chunks in flight",
+                file_key,
+                download.completed(),
+                download.total(),
+                download.in_flight()
+            )
+        });
+        let finished = self
+            .download_outcomes
+            .iter()
+            .map(|(file_key, outcome)| format!("{}: {}", file_key, outcome));
+        in_progress.chain(finished).collect()
+    }
+}
@@ -0,0 +1,378 @@
+// Command line client for a node started with --api. The private keys of users stay on
+// this machine: requests are signed here and only the signatures are sent to the node.
+use kv_storage::api::{self, ApiRequest, ApiResponse};
+use kv_storage::request::{self, Operation};
+use kv_storage::file::{FileManifest, Redundancy};
+use kv_storage::{client, crypto, keyfile};
+use std::path::Path;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let mut node = api::DEFAULT_API_ADDR.to_string();
+    if let ["--node", addr, ..] = args.as_slice() {
+        node = addr.to_string();
+        args.drain(..2);
+    }
+
+    match run(&node, &args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(node: &str, args: &[&str]) -> Result<(), String> {
+    match args {
+        ["register", username, flags @ ..] if flags.is_empty() || flags == ["--admin"] => {
+            let admin = !flags.is_empty();
+            // A key made beforehand with keygen is registered as it is, and kept if the node refuses it
+            if keyfile::exists(username) {
+                let passphrase = client::read_passphrase(&format!("Passphrase of {}: ", username), false)?;
+                let public_key = client::public_key(username, &passphrase)?;
+                let content_hash = request::content_hash(&public_key);
+                let (_, signature) = client::sign_request(username, &passphrase, Operation::Register, username, Some(content_hash))?;
+                let request = ApiRequest::Register { username: username.to_string(), public_key: public_key.clone(), signature, admin };
+                send(node, &request)?;
+                println!("Public Key (hex): {}", hex::encode(&public_key));
+                return Ok(());
+            }
+            let passphrase = client::read_passphrase("Passphrase to encrypt the private key: ", true)?;
+            let (public_key, signature) = client::create_user(username, &passphrase)?;
+            register(node, username, &public_key, signature, admin)?;
+            println!("Public Key (hex): {}", hex::encode(&public_key));
+            Ok(())
+        }
+        ["keygen", username] => {
+            let passphrase = client::read_passphrase("Passphrase to encrypt the private key: ", true)?;
+            let (public_key, _) = client::create_user(username, &passphrase)?;
+            println!("Public Key (hex): {}", hex::encode(&public_key));
+            Ok(())
+        }
+        ["import-key", username, file_path, flags @ ..]
+            if flags.is_empty() || flags == ["--register"] || flags == ["--register", "--admin"] =>
+        {
+            let secret_key = client::read_key_file(Path::new(file_path), || {
+                client::read_passphrase("Passphrase of the key file: ", false)
+            })?;
+            let passphrase = client::read_passphrase("Passphrase to encrypt the private key: ", true)?;
+            let (public_key, signature) = client::save_key(username, &secret_key, &passphrase)?;
+            if !flags.is_empty() {
+                register(node, username, &public_key, signature, flags.len() == 2)?;
+            }
+            println!("Public Key (hex): {}", hex::encode(&public_key));
+            Ok(())
+        }
+        ["export-key", username] => {
+            let passphrase = client::read_passphrase(&format!("Passphrase of {}: ", username), false)?;
+            println!("Public Key (hex): {}", hex::encode(client::public_key(username, &passphrase)?));
+            Ok(())
+        }
+        ["export-key", username, "--private", file_path] => {
+            let passphrase = client::read_passphrase(&format!("Passphrase of {}: ", username), false)?;
+            let export_passphrase = client::read_passphrase("Passphrase to encrypt the exported key: ", true)?;
+            client::export_key(username, &passphrase, Path::new(file_path), &export_passphrase)?;
+            println!("Private key of {} saved to {}", username, file_path);
+            Ok(())
+        }
+        ["rotate-key", username] => {
+            let passphrase = client::read_passphrase(&format!("Passphrase of {}: ", username), false)?;
+            let (request, new_secret_key) = client::rotate_key(username, &passphrase)?;
+
+            // Saved next to the old key until the node accepted the new one, so neither can be lost
+            let path = keyfile::path(username);
+            let pending = path.with_extension("private_key.new");
+            keyfile::write_to(&pending, &new_secret_key, &passphrase)?;
+            match client::call(node, &request) {
+                // Only a refusal means the node kept the old key
+                Ok(ApiResponse::Error(e)) => {
+                    let _ = std::fs::remove_file(&pending);
+                    return Err(e);
+                }
+                Ok(response) => print_response(response)?,
+                Err(e) => {
+                    return Err(format!(
+                        "No answer from the node ({}), it may have replaced the key. The new private key is kept in {}: \
+                         if `kv-client users {}` lists the old key as replaced, move it over {}",
+                        e,
+                        pending.display(),
+                        username,
+                        path.display()
+                    ))
+                }
+            }
+            std::fs::rename(&pending, &path).map_err(|e| {
+                format!("The node accepted the new key but it could not replace the old one, it is kept in {}: {}", pending.display(), e)
+            })
+        }
+        ["sign", username, "put-file", key, file_path, options @ ..] => {
+            // The node rebuilds the manifest from the same file, which it cannot do for an encrypted one
+            let (redundancy, encrypt) = parse_file_options(options)?;
+            if encrypt {
+                return Err("Encrypted files are stored with put-file, which encrypts them before they are sent".to_string());
+            }
+            let seq = client::next_seq(node, Operation::PutFile, key)?;
+            let passphrase = client::read_passphrase(&format!("Passphrase of {}: ", username), false)?;
+            match client::put_file(username, &passphrase, key, file_path, redundancy, None, seq)? {
+                ApiRequest::PutFile { public_key, signature, .. } => {
+                    println!("Public Key (hex): {}", hex::encode(&public_key));
+                    println!("Signature (hex): {}", hex::encode(&signature));
+                    Ok(())
+                }
+                _ => Err("Unexpected request".to_string()),
+            }
+        }
+        ["sign", username, op, key, content @ ..] => {
+            let op = Operation::parse(op).ok_or(
+                "Unknown operation, expected put, get, put-file, get-file, delete, grant, revoke, acl, acl-user, role, group-add, group-remove, rotate-key, register, audit or recipients",
+            )?;
+            let content_hash = client::content_hash(op, content)?;
+            // A write is signed as the one following the latest write the node finds
+            let seq = if op.is_write() { client::next_seq(node, op, key)? } else { 0 };
+            let passphrase = client::read_passphrase(&format!("Passphrase of {}: ", username), false)?;
+            let (public_key, signature) = client::sign_write(username, &passphrase, op, key, content_hash, seq)?;
+            println!("Public Key (hex): {}", hex::encode(&public_key));
+            println!("Signature (hex): {}", hex::encode(&signature));
+            Ok(())
+        }
+        ["put", username, key, value] => {
+            let content_hash = request::content_hash(value.as_bytes());
+            let seq = client::next_seq(node, Operation::Put, key)?;
+            let passphrase = client::read_passphrase(&format!("Passphrase of {}: ", username), false)?;
+            let (public_key, signature) = client::sign_write(username, &passphrase, Operation::Put, key, Some(content_hash), seq)?;
+            let value = value.as_bytes().to_vec();
+            send(node, &ApiRequest::Put { key: key.to_string(), value, public_key, signature })
+        }
+        ["get", username, key] => {
+            let (public_key, signature) = sign(username, Operation::Get, key, None)?;
+            send(node, &ApiRequest::Get { key: key.to_string(), public_key, signature })
+        }
+        ["put-file", username, key, file_path, options @ ..] => {
+            let (redundancy, encrypt) = parse_file_options(options)?;
+            // Asked once: the key signs both requests
+            let passphrase = client::read_passphrase(&format!("Passphrase of {}: ", username), false)?;
+
+            // The file key is wrapped for everyone the node knows is permitted to read the file
+            let recipients = if encrypt {
+                let (public_key, signature) = client::sign_request(username, &passphrase, Operation::Recipients, key, None)?;
+                match client::call(node, &ApiRequest::Recipients { key: key.to_string(), public_key, signature })? {
+                    ApiResponse::Keys(recipients) => Some(recipients),
+                    ApiResponse::Error(e) => return Err(e),
+                    _ => return Err("Unexpected answer from the node".to_string()),
+                }
+            } else {
+                None
+            };
+
+            let seq = client::next_seq(node, Operation::PutFile, key)?;
+            let request = client::put_file(username, &passphrase, key, file_path, redundancy, recipients.as_deref(), seq)?;
+            send(node, &request)
+        }
+        ["get-file", username, key] => {
+            // Asked once: the key is needed to sign the request and to unwrap the file key
+            let passphrase = client::read_passphrase(&format!("Passphrase of {}: ", username), false)?;
+
+            let (public_key, signature) = client::sign_request(username, &passphrase, Operation::GetFile, key, None)?;
+            let manifest = match client::call(node, &ApiRequest::GetFileManifest { key: key.to_string(), public_key, signature })? {
+                ApiResponse::Value(manifest) => FileManifest::decode(&manifest).ok_or("The node returned an invalid file manifest")?,
+                ApiResponse::Error(e) => return Err(e),
+                _ => return Err("Unexpected answer from the node".to_string()),
+            };
+            let file_key = match &manifest.wrapped_keys {
+                Some(wrapped_keys) => {
+                    let secret_key = client::load_private_key(username, &passphrase)?;
+                    let file_key = crypto::unwrap_key(wrapped_keys, &secret_key).map_err(|e| format!("Cannot decrypt file: {}", e))?;
+                    Some(file_key)
+                }
+                None => None,
+            };
+
+            let file_path = client::download_file(node, key, manifest, file_key)?;
+            println!("File written to {}", file_path.display());
+            Ok(())
+        }
+        ["delete", username, key] => {
+            let (public_key, signature) = sign(username, Operation::Delete, key, None)?;
+            send(node, &ApiRequest::Delete { key: key.to_string(), public_key, signature })
+        }
+        ["grant", username, key, subject, permissions] => {
+            let content_hash = client::content_hash(Operation::Grant, &[subject, permissions])?;
+            let (public_key, signature) = sign(username, Operation::Grant, key, content_hash)?;
+            send(
+                node,
+                &ApiRequest::Grant {
+                    key: key.to_string(),
+                    subject: subject.to_string(),
+                    permissions: permissions.to_string(),
+                    public_key,
+                    signature,
+                },
+            )
+        }
+        ["revoke", username, key, subject] => {
+            let content_hash = client::content_hash(Operation::Revoke, &[subject])?;
+            let (public_key, signature) = sign(username, Operation::Revoke, key, content_hash)?;
+            send(node, &ApiRequest::Revoke { key: key.to_string(), subject: subject.to_string(), public_key, signature })
+        }
+        ["acl", username, key] => {
+            let (public_key, signature) = sign(username, Operation::ListAcl, key, None)?;
+            send(node, &ApiRequest::Acl { key: key.to_string(), public_key, signature })
+        }
+        ["acl-user", username, user] => {
+            let user_key = decode_public_key(user)?;
+            let (public_key, signature) = sign(username, Operation::ListUserAcl, user, None)?;
+            send(node, &ApiRequest::UserAcl { user: user_key, public_key, signature })
+        }
+        ["role", username, user, role] => {
+            let user_key = decode_public_key(user)?;
+            let content_hash = client::content_hash(Operation::SetRole, &[role])?;
+            let (public_key, signature) = sign(username, Operation::SetRole, user, content_hash)?;
+            send(node, &ApiRequest::SetRole { user: user_key, role: role.to_string(), public_key, signature })
+        }
+        ["group", username, action, group, user] if *action == "add" || *action == "remove" => {
+            let user_key = decode_public_key(user)?;
+            let op = if *action == "add" { Operation::GroupAdd } else { Operation::GroupRemove };
+            let content_hash = client::content_hash(op, &[user])?;
+            let (public_key, signature) = sign(username, op, group, content_hash)?;
+            let request = ApiRequest::GroupMember {
+                group: group.to_string(),
+                user: user_key,
+                member: op == Operation::GroupAdd,
+                public_key,
+                signature,
+            };
+            send(node, &request)
+        }
+        ["audit", username, key @ ..] if key.len() <= 1 => {
+            let key = key.first().copied();
+            let (public_key, signature) = sign(username, Operation::Audit, key.unwrap_or(""), None)?;
+            send(node, &ApiRequest::Audit { key: key.map(|key| key.to_string()), public_key, signature })
+        }
+        ["users", username @ ..] if username.len() <= 1 => {
+            let request = ApiRequest::Users { username: username.first().map(|username| username.to_string()) };
+            match client::call(node, &request)? {
+                ApiResponse::Lines(lines) if lines.is_empty() => {
+                    println!("No users");
+                    Ok(())
+                }
+                response => print_response(response),
+            }
+        }
+        ["downloads"] => match client::call(node, &ApiRequest::Downloads)? {
+            ApiResponse::Lines(lines) if lines.is_empty() => {
+                println!("No downloads");
+                Ok(())
+            }
+            response => print_response(response),
+        },
+        ["peers"] => match client::call(node, &ApiRequest::Peers)? {
+            ApiResponse::Lines(lines) if lines.is_empty() => {
+                println!("No peers");
+                Ok(())
+            }
+            response => print_response(response),
+        },
+        _ => Err(usage()),
+    }
+}
+
+// Register a key saved for `username` with the node. A key the node refused is of no use,
+// so it is dropped and the name can be used again.
+fn register(node: &str, username: &str, public_key: &[u8], signature: Vec<u8>, admin: bool) -> Result<(), String> {
+    let request = ApiRequest::Register { username: username.to_string(), public_key: public_key.to_vec(), signature, admin };
+    send(node, &request).inspect_err(|_| {
+        let _ = std::fs::remove_file(keyfile::path(username));
+    })
+}
+
+// Ask for the passphrase of `username` and sign a request with the user's private key
+fn sign(username: &str, op: Operation, key: &str, content_hash: Option<[u8; 32]>) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let passphrase = client::read_passphrase(&format!("Passphrase of {}: ", username), false)?;
+    client::sign_request(username, &passphrase, op, key, content_hash)
+}
+
+fn send(node: &str, request: &ApiRequest) -> Result<(), String> {
+    print_response(client::call(node, request)?)
+}
+
+fn print_response(response: ApiResponse) -> Result<(), String> {
+    match response {
+        ApiResponse::Done(message) => println!("{}", message),
+        ApiResponse::Value(value) => println!("{}", String::from_utf8_lossy(&value)),
+        ApiResponse::Lines(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        ApiResponse::Sequence(seq) => println!("{}", seq),
+        ApiResponse::Keys(keys) => {
+            for key in keys {
+                println!("{}", hex::encode(key));
+            }
+        }
+        ApiResponse::Error(e) => return Err(e),
+    }
+    Ok(())
+}
+
+fn decode_public_key(public_key: &str) -> Result<Vec<u8>, String> {
+    hex::decode(public_key).map_err(|_| "Invalid public key format".to_string())
+}
+
+// The `--erasure <data>+<parity>` and `--encrypt` options of put-file
+fn parse_file_options(options: &[&str]) -> Result<(Redundancy, bool), String> {
+    let mut redundancy = Redundancy::Replicated;
+    let mut encrypt = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--encrypt" => encrypt = true,
+            "--erasure" => match options.next().and_then(|spec| Redundancy::parse_erasure(spec)) {
+                Some(parsed) => redundancy = parsed,
+                None => return Err("Invalid erasure spec, expected <data>+<parity> such as 4+2".to_string()),
+            },
+            _ => return Err(usage()),
+        }
+    }
+    Ok((redundancy, encrypt))
+}
+
+fn usage() -> String {
+    [
+        "Usage: kv-client [--node <address>] <command>, the node defaults to 127.0.0.1:7400",
+        "Commands:",
+        "  register <username> [--admin]              - Make a key pair, or take the one made by keygen, and register it",
+        "                                               (--admin: a key the node was started with --admin-key for)",
+        "  keygen <username>                          - Make a key pair without registering it, e.g. for --admin-key",
+        "  put <username> <key> <value>               - Store a key-value pair",
+        "  get <username> <key>                       - Retrieve a key-value pair",
+        "  put-file <username> <key> <file_path> [--erasure <k>+<m>] [--encrypt]",
+        "                                             - Store a file, optionally erasure coded and encrypted",
+        "                                               (split and encrypted here, before it is sent)",
+        "  get-file <username> <key>                  - Retrieve a file into the current directory",
+        "  delete <username> <key>                    - Delete the value and file stored under a key",
+        "  grant <username> <key> <subject> <perms>   - Give a user (pk) or group (group:<name>) permissions",
+        "  revoke <username> <key> <subject>          - Withdraw permissions on a key",
+        "  acl <username> <key>                       - List who is permitted on a key",
+        "  acl-user <username> <pk>                   - List the keys a user is permitted on",
+        "  role <username> <pk> <admin|writer|reader> - Change the role of a user",
+        "  group <username> add|remove <group> <pk>   - Add a user to or remove them from a group",
+        "  import-key <username> <file> [--register [--admin]]",
+        "                                             - Save an existing ed25519 private key (exported, raw or hex),",
+        "                                               and register it with --register",
+        "  export-key <username> [--private <file>]   - Print the public key, or save the private key encrypted",
+        "                                               under a new passphrase to <file>",
+        "  rotate-key <username>                      - Replace the key pair, signed by the old key",
+        "  sign <username> <op> <key> [<content>..]   - Only sign a request, for the node's own prompt",
+        "                                               (put-file: the file path and --erasure, if used)",
+        "  audit <username> [<key>]                   - Show the audit log, or its entries for a key (admins only)",
+        "  users [<username>]                         - List the users, or one user, with their keys and roles",
+        "  downloads                                  - Show the progress of downloads started on the node's prompt",
+        "  peers                                      - Show which peers the node is connected to or saw lately",
+    ]
+    .join("\n")
+}
@@ -1,40 +1,325 @@
 use ed25519_dalek::{Keypair, Signature, SecretKey, PublicKey};
 use ed25519_dalek::Signer;
-use std::fs::File;
-use std::io::{Read};
-use std::io::ErrorKind;
+use crate::access::{self, Permissions, Role, Subject};
+use crate::api::{self, ApiRequest, ApiResponse};
+use crate::crypto::FileKey;
+use crate::download::{Download, DEFAULT_DOWNLOAD_WINDOW};
+use crate::file::{self, FileManifest, Redundancy};
+use crate::request::{self, Operation, RequestSignature};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use crate::keyfile;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Instant;
 
-pub fn sign_message(username: &str, message: &str) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
-    // load the private key
-    let private_key_path = format!("./private_keys/{}.private_key", username);
+/// Make a key pair for a new user and save the private key encrypted under `passphrase`.
+/// Returns the public key and the user's signed `register` request, to send to a node.
+pub fn create_user(username: &str, passphrase: &str) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let keypair = Keypair::generate(&mut OsRng);
+    save_key(username, keypair.secret.as_bytes(), passphrase)
+}
+
+/// Save an existing ed25519 private key for `username`, encrypted under `passphrase`.
+/// Returns the public key and the user's signed `register` request, like `create_user`.
+pub fn save_key(username: &str, secret_key: &[u8], passphrase: &str) -> Result<(Vec<u8>, Vec<u8>), String> {
+    if keyfile::exists(username) {
+        return Err(format!("A private key for '{}' already exists", username));
+    }
+
+    let keypair = keypair(secret_key)?;
+    let public_key = keypair.public.to_bytes().to_vec();
+    keyfile::write(username, secret_key, passphrase)?;
+
+    let signature = sign_with(&keypair, Operation::Register, username, Some(&request::content_hash(&public_key)), 0, request::unix_now());
+    Ok((public_key, signature))
+}
+
+/// Load the private key saved when `username` was registered, decrypting it with `passphrase`
+pub fn load_private_key(username: &str, passphrase: &str) -> Result<Vec<u8>, String> {
+    keyfile::read(username, passphrase)
+}
+
+/// Public key of `username`, whose private key is only stored encrypted
+pub fn public_key(username: &str, passphrase: &str) -> Result<Vec<u8>, String> {
+    let keypair = keypair(&load_private_key(username, passphrase)?)?;
+    Ok(keypair.public.to_bytes().to_vec())
+}
+
+/// Read a private key to import: a file written by export-key, whose passphrase is asked
+/// for, or a raw 32 byte ed25519 private key, as bytes or in hex
+pub fn read_key_file(path: &Path, ask_passphrase: impl FnOnce() -> Result<String, String>) -> Result<Vec<u8>, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Failed to read '{}': {}", path.display(), e))?;
+    let secret_key = if keyfile::is_encrypted(&bytes) {
+        keyfile::decrypt(&path.display().to_string(), &bytes, &ask_passphrase()?)?
+    } else if bytes.len() == 32 {
+        bytes
+    } else {
+        let text = String::from_utf8_lossy(&bytes);
+        hex::decode(text.trim())
+            .ok()
+            .filter(|secret_key| secret_key.len() == 32)
+            .ok_or("Not an ed25519 private key: expected an exported key file or 32 bytes, raw or in hex")?
+    };
+    // Fails for anything that is not a valid ed25519 private key
+    keypair(&secret_key)?;
+    Ok(secret_key)
+}
+
+/// Save the private key of `username` to `path`, encrypted under `export_passphrase`,
+/// as a backup that import-key can read
+pub fn export_key(username: &str, passphrase: &str, path: &Path, export_passphrase: &str) -> Result<(), String> {
+    let secret_key = load_private_key(username, passphrase)?;
+    keyfile::write_to(path, &secret_key, export_passphrase)
+}
+
+/// Make a new key pair to replace the one of `username`. Returns the rotate-key request,
+/// signed by the old key, and the new private key, to save once the node accepted it.
+pub fn rotate_key(username: &str, passphrase: &str) -> Result<(ApiRequest, Vec<u8>), String> {
+    let new_keypair = Keypair::generate(&mut OsRng);
+    let new_public_key = new_keypair.public.to_bytes().to_vec();
+    let new_signature = sign_with(
+        &new_keypair,
+        Operation::Register,
+        username,
+        Some(&request::content_hash(&new_public_key)),
+        0,
+        request::unix_now(),
+    );
+
+    let old_public_key = public_key(username, passphrase)?;
+    let content_hash = request::content_hash(&new_public_key);
+    let (public_key, signature) =
+        sign_request(username, passphrase, Operation::RotateKey, &hex::encode(old_public_key), Some(content_hash))?;
 
-    let mut private_key_bytes = Vec::new();
-    match File::open(&private_key_path) {
-        Ok(mut file) => {
-            file.read_to_end(&mut private_key_bytes).map_err(|e| e.to_string())?;
+    let request = ApiRequest::RotateKey {
+        public_key,
+        signature,
+        new_public_key,
+        new_signature,
+    };
+    Ok((request, new_keypair.secret.as_bytes().to_vec()))
+}
+
+// The key pair of an ed25519 private key
+fn keypair(secret_key: &[u8]) -> Result<Keypair, String> {
+    let secret = SecretKey::from_bytes(secret_key).map_err(|e| e.to_string())?;
+    let public: PublicKey = (&secret).into();
+    Ok(Keypair { secret, public })
+}
+
+/// Ask for a passphrase on the terminal without echoing it. With `confirm` it is asked
+/// twice, for passphrases that are being chosen.
+pub fn read_passphrase(prompt: &str, confirm: bool) -> Result<String, String> {
+    let passphrase = rpassword::prompt_password(prompt).map_err(|e| e.to_string())?;
+    if confirm {
+        let repeated = rpassword::prompt_password("Repeat passphrase: ").map_err(|e| e.to_string())?;
+        if repeated != passphrase {
+            return Err("Passphrases do not match".to_string());
+        }
+    }
+    Ok(passphrase)
+}
+
+/// Hash of the content a request for `op` signs, from the words typed after the key:
+/// the value for put, the subject and permissions for grant, the subject for revoke, the role
+/// for role, the public key for group changes and registration and the new public key for
+/// rotate-key. put-file signs the file's manifest, see `put_file`.
+pub fn content_hash(op: Operation, content: &[&str]) -> Result<Option<[u8; 32]>, String> {
+    let hash = match (op, content) {
+        (Operation::Put, [value]) => request::content_hash(value.as_bytes()),
+        (Operation::Grant, [subject, permissions]) => match (Subject::parse(subject), Permissions::parse(permissions)) {
+            (Some(subject), Some(permissions)) => access::grant_content_hash(&subject, permissions),
+            _ => return Err("Invalid subject or permissions".to_string()),
         },
-        Err(ref e) if e.kind() == ErrorKind::NotFound => {
-            return Err("This client is not registered".to_string());
+        (Operation::Revoke, [subject]) => match Subject::parse(subject) {
+            Some(subject) => access::revoke_content_hash(&subject),
+            None => return Err("Invalid subject, expected a public key or group:<name>".to_string()),
         },
-        Err(e) => {
-            return Err(e.to_string());
+        (Operation::SetRole, [role]) => match Role::parse(role) {
+            Some(role) => request::content_hash(role.as_str().as_bytes()),
+            None => return Err("Unknown role, expected admin, writer or reader".to_string()),
+        },
+        (Operation::GroupAdd | Operation::GroupRemove | Operation::RotateKey | Operation::Register, [public_key]) => match hex::decode(public_key) {
+            Ok(pk) => request::content_hash(&pk),
+            Err(_) => return Err("Invalid public key format".to_string()),
+        },
+        (
+            Operation::Get
+            | Operation::GetFile
+            | Operation::Delete
+            | Operation::ListAcl
+            | Operation::ListUserAcl
+            | Operation::Audit
+            | Operation::Recipients,
+            [],
+        ) => {
+            return Ok(None)
+        }
+        _ => {
+            return Err("put needs the value, put-file the file path, grant the subject and permissions, revoke the subject, role the role, group-add and group-remove the public key, rotate-key the new public key and register the public key to sign; get, get-file, delete, acl, acl-user, audit and recipients take nothing".to_string())
+        }
+    };
+    Ok(Some(hash))
+}
+
+/// Send a request to the node listening for clients on `addr` and wait for its answer
+pub fn call(addr: &str, request: &ApiRequest) -> Result<ApiResponse, String> {
+    let mut stream = TcpStream::connect(addr).map_err(|e| format!("Cannot reach node at {}: {}", addr, e))?;
+    stream.write_all(&api::encode_frame(request)?).map_err(|e| e.to_string())?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).map_err(|e| e.to_string())?;
+    let len = api::frame_len(header).ok_or("Response too large")?;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).map_err(|e| e.to_string())?;
+    api::decode_frame(&body).ok_or_else(|| "Malformed response".to_string())
+}
+
+/// Sign a request for `op` on `key`. Writes, grants and role changes also sign the hash
+/// of their content. Returns the public key and the encoded `RequestSignature` to pass to the node.
+pub fn sign_request(username: &str, passphrase: &str, op: Operation, key: &str, content_hash: Option<[u8; 32]>) -> Result<(Vec<u8>, Vec<u8>), String> {
+    sign_write(username, passphrase, op, key, content_hash, 0)
+}
+
+/// Sign a put request like `sign_request`, as the write with sequence number `seq` to the
+/// key (see `next_seq`)
+pub fn sign_write(
+    username: &str,
+    passphrase: &str,
+    op: Operation,
+    key: &str,
+    content_hash: Option<[u8; 32]>,
+    seq: u64,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    if op.signs_content() != content_hash.is_some() {
+        return Err(format!("A {} request {} content to sign", op.as_str(), if op.signs_content() { "needs" } else { "takes no" }));
+    }
+
+    // load the private key
+    let keypair = keypair(&load_private_key(username, passphrase)?)?;
+
+    // print all the thing the user need to make file operations
+    let signature = sign_with(&keypair, op, key, content_hash.as_ref(), seq, request::unix_now());
+    Ok((keypair.public.to_bytes().to_vec(), signature))
+}
+
+/// Sequence number for the next `op` (put or put-file) on `key`: one more than that of the
+/// latest write the node on `addr` finds stored under the key, 1 for a key never written
+pub fn next_seq(addr: &str, op: Operation, key: &str) -> Result<u64, String> {
+    let request = ApiRequest::Sequence { key: key.to_string(), file: op == Operation::PutFile };
+    match call(addr, &request)? {
+        ApiResponse::Sequence(seq) => Ok(seq + 1),
+        ApiResponse::Error(e) => Err(e),
+        _ => Err("Unexpected answer from the node".to_string()),
+    }
+}
+
+/// Split the file at `file_path` into chunks, encrypted under a fresh file key wrapped for
+/// `recipients` if given, and sign the put-file request for its manifest as the write with
+/// sequence number `seq`. Neither the file nor its key leaves this machine unencrypted.
+/// Returns the request to send to the node.
+pub fn put_file(
+    username: &str,
+    passphrase: &str,
+    key: &str,
+    file_path: &str,
+    redundancy: Redundancy,
+    recipients: Option<&[Vec<u8>]>,
+    seq: u64,
+) -> Result<ApiRequest, String> {
+    let keypair = keypair(&load_private_key(username, passphrase)?)?;
+    let public_key = keypair.public.to_bytes().to_vec();
+    let content = std::fs::read(file_path).map_err(|e| format!("Failed to read file '{}': {}", file_path, e))?;
+
+    let timestamp = request::unix_now();
+    let name = file::stored_name(file_path, key);
+    let (manifest, chunks) = FileManifest::build(name, &content, public_key.clone(), redundancy, recipients, timestamp)?;
+    let manifest = manifest.encode();
+    let signature = sign_with(&keypair, Operation::PutFile, key, Some(&request::content_hash(&manifest)), seq, timestamp);
+    Ok(ApiRequest::PutFile { key: key.to_string(), manifest, chunks, public_key, signature })
+}
+
+/// Fetch the chunks `manifest` lists through the node on `addr` and write the file to the
+/// current directory, decrypting it with `file_key` if it is encrypted. Up to
+/// DEFAULT_DOWNLOAD_WINDOW chunks are fetched at a time and an interrupted download is
+/// resumed by the next one (see `Download`). Returns where the file was written.
+pub fn download_file(addr: &str, key: &str, manifest: FileManifest, file_key: Option<FileKey>) -> Result<PathBuf, String> {
+    let file_path = std::env::current_dir().map_err(|e| e.to_string())?.join(manifest.local_file_name(key));
+    let mut download = Download::open(manifest, file_path, file_key).map_err(|e| format!("Failed to create file: {}", e))?;
+    let shard = matches!(download.manifest.redundancy, Redundancy::ErasureCoded { .. });
+
+    while !download.is_complete() {
+        let requests: Vec<(usize, [u8; 32], u32)> = download
+            .next_requests(DEFAULT_DOWNLOAD_WINDOW)
+            .into_iter()
+            .map(|index| (index, download.manifest.chunks[index], download.attempts(index)))
+            .collect();
+        if requests.is_empty() {
+            // Only chunks waiting for a retry are left
+            let retry_at = download.next_retry_at().ok_or("Download stalled")?;
+            thread::sleep(retry_at.saturating_duration_since(Instant::now()));
+            continue;
         }
+
+        let fetched: Vec<(usize, Option<Vec<u8>>)> = thread::scope(|scope| {
+            let fetches: Vec<_> = requests
+                .into_iter()
+                .map(|(index, hash, attempt)| scope.spawn(move || (index, fetch_chunk(addr, hash, shard, attempt))))
+                .collect();
+            fetches.into_iter().map(|fetch| fetch.join().expect("Chunk fetch panicked")).collect()
+        });
+        for (index, chunk) in fetched {
+            // The other shards of a stripe are dropped once it could be rebuilt
+            if !download.is_in_flight(index) {
+                continue;
+            }
+            match chunk {
+                Some(chunk) => download
+                    .chunk_received(index, &chunk)
+                    .map_err(|e| format!("Could not write chunk {}: {}", index, e))?,
+                None if download.chunk_failed(index) => {}
+                None => {
+                    return Err(format!(
+                        "Chunk {} could not be retrieved after {} attempts, get-file again to resume",
+                        index,
+                        download.attempts(index)
+                    ))
+                }
+            }
+        }
+        println!("{}/{} parts written", download.completed(), download.total());
     }
 
-    let private_key = SecretKey::from_bytes(&private_key_bytes).map_err(|e| e.to_string())?;
-    let public_key: PublicKey = (&private_key).into();
-    let keypair = Keypair {
-        secret: private_key,
-        public: public_key,
-    };
+    download.finish().map_err(|e| format!("Verification failed: {}", e))?;
+    Ok(download.file_path)
+}
+
+// Ask the node for a chunk, None unless it returned a copy matching the hash
+fn fetch_chunk(addr: &str, hash: [u8; 32], shard: bool, attempt: u32) -> Option<Vec<u8>> {
+    match call(addr, &ApiRequest::GetChunk { hash, shard, attempt }) {
+        Ok(ApiResponse::Value(chunk)) if file::chunk_hash(&chunk) == hash => Some(chunk),
+        _ => None,
+    }
+}
+
+// Sign a request with `keypair` as of `timestamp`, returning the encoded `RequestSignature`
+fn sign_with(keypair: &Keypair, op: Operation, key: &str, content_hash: Option<&[u8; 32]>, seq: u64, timestamp: u64) -> Vec<u8> {
+    // A fresh nonce and the timestamp make the signature usable only once, and only for a while
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
 
     // sign the message with the private key
-    let signature: Signature = keypair.sign(message.as_bytes());
+    let message = request::signed_message(op, key, content_hash, seq, timestamp, &nonce);
+    let signature: Signature = keypair.sign(&message);
 
-    // print all the thing the user need to make file operations
-    Ok((
-        keypair.public.to_bytes().to_vec(),
-        signature.to_bytes().to_vec(),
-        message.as_bytes().to_vec()
-    ))
-}
\ No newline at end of file
+    let request_signature = RequestSignature {
+        timestamp,
+        nonce,
+        seq,
+        signature: signature.to_bytes().to_vec(),
+    };
+    request_signature.encode()
+}
@@ -0,0 +1,141 @@
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::constants::X25519_BASEPOINT;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use ed25519_dalek::{PublicKey, SecretKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+
+/// Symmetric key a single file's chunks are encrypted with
+pub type FileKey = [u8; 32];
+
+// Context string for deriving the key that wraps a file key
+const WRAP_CONTEXT: &str = "kv_storage 2024 file key wrap";
+
+/// The file key encrypted for one user. Users are identified by their ed25519 public key;
+/// the key is wrapped with X25519 between a fresh ephemeral key and the Montgomery
+/// form of that public key, so only the holder of the matching private key can unwrap it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    // ed25519 public key of the user the file key is wrapped for
+    pub recipient: Vec<u8>,
+    // X25519 public key of the ephemeral key used for this recipient
+    pub ephemeral: [u8; 32],
+    // The file key encrypted with ChaCha20-Poly1305 under the shared secret
+    pub ciphertext: Vec<u8>,
+}
+
+pub fn generate_file_key() -> FileKey {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+// Every chunk of a file is encrypted under the same key, the chunk index makes the nonce unique
+fn chunk_nonce(index: usize) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&(index as u64).to_le_bytes());
+    nonce
+}
+
+/// Encrypt chunk `index` of a file. The result is 16 bytes longer than the chunk.
+pub fn encrypt_chunk(key: &FileKey, index: usize, chunk: &[u8]) -> Result<Vec<u8>, String> {
+    ChaCha20Poly1305::new(&Key::from(*key))
+        .encrypt(&Nonce::from(chunk_nonce(index)), chunk)
+        .map_err(|_| format!("Failed to encrypt chunk {}", index))
+}
+
+/// Decrypt chunk `index` of a file, failing if it was not encrypted under `key`
+pub fn decrypt_chunk(key: &FileKey, index: usize, chunk: &[u8]) -> Result<Vec<u8>, String> {
+    ChaCha20Poly1305::new(&Key::from(*key))
+        .decrypt(&Nonce::from(chunk_nonce(index)), chunk)
+        .map_err(|_| format!("Failed to decrypt chunk {}", index))
+}
+
+/// Hash identifying the content of a file. Encrypted files use a hash keyed with the
+/// file key so the manifest does not reveal which plaintext it holds.
+pub fn content_hash(key: Option<&FileKey>, content: &[u8]) -> [u8; 32] {
+    match key {
+        Some(key) => *blake3::keyed_hash(key, content).as_bytes(),
+        None => *blake3::hash(content).as_bytes(),
+    }
+}
+
+/// Wrap `file_key` for the user owning the ed25519 public key `recipient`
+pub fn wrap_key(file_key: &FileKey, recipient: &[u8]) -> Result<WrappedKey, String> {
+    let recipient_point = montgomery_public_key(recipient)?;
+
+    let mut ephemeral_secret = [0u8; 32];
+    OsRng.fill_bytes(&mut ephemeral_secret);
+    let ephemeral_scalar = clamp(ephemeral_secret);
+    let ephemeral = (X25519_BASEPOINT * ephemeral_scalar).to_bytes();
+
+    let shared = recipient_point * ephemeral_scalar;
+    let wrap_key = derive_wrap_key(&shared, &ephemeral, recipient);
+    let ciphertext = ChaCha20Poly1305::new(&Key::from(wrap_key))
+        .encrypt(&Nonce::default(), file_key.as_ref())
+        .map_err(|_| "Failed to wrap file key".to_string())?;
+
+    Ok(WrappedKey {
+        recipient: recipient.to_vec(),
+        ephemeral,
+        ciphertext,
+    })
+}
+
+/// Find the file key wrapped for the owner of the ed25519 `secret_key` and unwrap it
+pub fn unwrap_key(wrapped_keys: &[WrappedKey], secret_key: &[u8]) -> Result<FileKey, String> {
+    let secret = SecretKey::from_bytes(secret_key).map_err(|e| e.to_string())?;
+    let public: PublicKey = (&secret).into();
+    let public = public.to_bytes();
+
+    let wrapped = wrapped_keys
+        .iter()
+        .find(|wrapped| wrapped.recipient == public)
+        .ok_or_else(|| "The file is not encrypted for this user".to_string())?;
+
+    // Same scalar ed25519 signs with, so the X25519 secret matches the converted public key
+    let hash = Sha512::digest(secret.as_bytes());
+    let mut scalar_bytes = [0u8; 32];
+    scalar_bytes.copy_from_slice(&hash[..32]);
+
+    let shared = MontgomeryPoint(wrapped.ephemeral) * clamp(scalar_bytes);
+    let wrap_key = derive_wrap_key(&shared, &wrapped.ephemeral, &public);
+    let file_key = ChaCha20Poly1305::new(&Key::from(wrap_key))
+        .decrypt(&Nonce::default(), wrapped.ciphertext.as_slice())
+        .map_err(|_| "Failed to unwrap file key".to_string())?;
+
+    file_key
+        .try_into()
+        .map_err(|_| "Unwrapped file key has the wrong length".to_string())
+}
+
+// Birational map from the ed25519 public key to its X25519 counterpart
+fn montgomery_public_key(public_key: &[u8]) -> Result<MontgomeryPoint, String> {
+    if public_key.len() != 32 {
+        return Err("Invalid public key length".to_string());
+    }
+    CompressedEdwardsY::from_slice(public_key)
+        .decompress()
+        .map(|point| point.to_montgomery())
+        .ok_or_else(|| "Invalid public key".to_string())
+}
+
+fn clamp(mut bytes: [u8; 32]) -> Scalar {
+    bytes[0] &= 248;
+    bytes[31] &= 127;
+    bytes[31] |= 64;
+    Scalar::from_bits(bytes)
+}
+
+fn derive_wrap_key(shared: &MontgomeryPoint, ephemeral: &[u8; 32], recipient: &[u8]) -> [u8; 32] {
+    let mut material = Vec::with_capacity(96);
+    material.extend_from_slice(shared.as_bytes());
+    material.extend_from_slice(ephemeral);
+    material.extend_from_slice(recipient);
+    blake3::derive_key(WRAP_CONTEXT, &material)
+}
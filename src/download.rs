@@ -0,0 +1,353 @@
+use crate::crypto::{self, FileKey};
+use crate::erasure;
+use crate::file::{FileManifest, Redundancy};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Default number of chunks fetched at the same time for one download
+pub const DEFAULT_DOWNLOAD_WINDOW: usize = 16;
+
+/// How many times a chunk is fetched before the whole download is given up
+pub const MAX_CHUNK_ATTEMPTS: u32 = 5;
+
+// Wait before the first retry of a chunk, doubled on every further failure
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChunkState {
+    // Not requested yet
+    Pending,
+    // A query for the chunk is running
+    InFlight,
+    // The last fetch failed, the chunk is requested again once the instant is reached
+    Backoff(Instant),
+    // Retrieved (and written to the file unless still buffered in its stripe)
+    Done,
+    // Parity shard kept in reserve until a shard of its stripe fails
+    Spare,
+    // No longer needed: replaced by a spare, or its stripe was rebuilt without it
+    Dropped,
+}
+
+// Sidecar file kept next to a partial download so it can be resumed after a restart
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    // Identifies the file the progress belongs to
+    content_hash: [u8; 32],
+    // Done flag of every unit, see Download::units_done
+    done: Vec<bool>,
+}
+
+/// How a download ended
+#[derive(Debug, Clone)]
+pub enum DownloadOutcome {
+    Completed(PathBuf),
+    Failed(String),
+}
+
+impl fmt::Display for DownloadOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadOutcome::Completed(path) => write!(f, "completed, written to '{}'", path.display()),
+            DownloadOutcome::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+/// State of a file being downloaded.
+///
+/// Up to `window` chunks are in flight at once. Chunks can arrive in any order and are
+/// written straight to their offset in the output file, with progress recorded in a
+/// `<file>.progress` sidecar. A failed chunk is retried with exponential backoff,
+/// and the download fails once a chunk has used up MAX_CHUNK_ATTEMPTS.
+///
+/// Progress is tracked in units: a chunk for replicated files, a stripe for erasure
+/// coded ones. Only the data shards of a stripe are requested at first; when one fails
+/// a parity shard is requested instead, and the stripe is rebuilt from the first
+/// `data_shards` shards that arrive.
+pub struct Download {
+    pub manifest: FileManifest,
+    pub file_path: PathBuf,
+    progress_path: PathBuf,
+    states: Vec<ChunkState>,
+    // How many times each chunk has been requested
+    attempts: Vec<u32>,
+    // Peers that returned a chunk not matching its hash, their copies are ignored from then on
+    bad_peers: HashSet<PeerId>,
+    // Whether each unit is written to the file
+    units_done: Vec<bool>,
+    // Number of units written to the file
+    completed: usize,
+    // Shards received for erasure coded stripes that cannot be rebuilt yet
+    stripes: HashMap<usize, Vec<Option<Vec<u8>>>>,
+    // Key the chunks are decrypted with, for encrypted files
+    file_key: Option<FileKey>,
+}
+
+impl Download {
+    /// Prepare the output file. If a progress sidecar for the same content is found next
+    /// to it, the chunks it lists are kept and only the missing ones are fetched.
+    /// `file_key` is the unwrapped key of an encrypted file.
+    pub fn open(manifest: FileManifest, file_path: PathBuf, file_key: Option<FileKey>) -> io::Result<Self> {
+        let mut progress_path = file_path.clone().into_os_string();
+        progress_path.push(".progress");
+        let progress_path = PathBuf::from(progress_path);
+        let total_chunks = manifest.chunks.len();
+        let (width, data) = layout(&manifest);
+        let total_units = total_chunks.div_ceil(width);
+
+        // Parity shards wait in reserve until they are needed
+        let mut states: Vec<ChunkState> = (0..total_chunks)
+            .map(|index| if index % width < data { ChunkState::Pending } else { ChunkState::Spare })
+            .collect();
+        let mut units_done = vec![false; total_units];
+        let mut completed = 0;
+        match Self::load_progress(&progress_path, &manifest, &file_path, total_units) {
+            Some(done) => {
+                for (unit, done) in done.into_iter().enumerate() {
+                    if done {
+                        units_done[unit] = true;
+                        completed += 1;
+                        for state in states.iter_mut().skip(unit * width).take(width) {
+                            *state = ChunkState::Done;
+                        }
+                    }
+                }
+                println!(
+                    "Resuming download of '{}': {} of {} parts already on disk.",
+                    file_path.display(), completed, total_units
+                );
+            }
+            None => {
+                let file = File::create(&file_path)?;
+                file.set_len(manifest.size)?;
+            }
+        }
+
+        Ok(Download {
+            manifest,
+            file_path,
+            progress_path,
+            states,
+            attempts: vec![0; total_chunks],
+            bad_peers: HashSet::new(),
+            units_done,
+            completed,
+            stripes: HashMap::new(),
+            file_key,
+        })
+    }
+
+    fn load_progress(progress_path: &Path, manifest: &FileManifest, file_path: &Path, total_units: usize) -> Option<Vec<bool>> {
+        let progress: Progress = bincode::deserialize(&std::fs::read(progress_path).ok()?).ok()?;
+        let file_len = std::fs::metadata(file_path).ok()?.len();
+        if progress.content_hash != manifest.content_hash
+            || progress.done.len() != total_units
+            || file_len != manifest.size
+        {
+            return None;
+        }
+        Some(progress.done)
+    }
+
+    fn save_progress(&self) -> io::Result<()> {
+        let progress = Progress {
+            content_hash: self.manifest.content_hash,
+            done: self.units_done.clone(),
+        };
+        let bytes = bincode::serialize(&progress).map_err(io::Error::other)?;
+        std::fs::write(&self.progress_path, bytes)
+    }
+
+    /// Number of units (chunks, or stripes when erasure coded) in the file
+    pub fn total(&self) -> usize {
+        self.units_done.len()
+    }
+
+    /// Number of units already written to the file
+    pub fn completed(&self) -> usize {
+        self.completed
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.states.iter().filter(|state| **state == ChunkState::InFlight).count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed == self.total()
+    }
+
+    /// Chunks to request so that `window` of them are in flight. Failed chunks are
+    /// only picked again once their backoff is over.
+    pub fn next_requests(&mut self, window: usize) -> Vec<usize> {
+        let now = Instant::now();
+        let mut in_flight = self.in_flight();
+        let mut requests = Vec::new();
+
+        for (index, state) in self.states.iter_mut().enumerate() {
+            if in_flight >= window {
+                break;
+            }
+            let ready = match *state {
+                ChunkState::Pending => true,
+                ChunkState::Backoff(retry_at) => retry_at <= now,
+                _ => false,
+            };
+            if ready {
+                *state = ChunkState::InFlight;
+                self.attempts[index] += 1;
+                in_flight += 1;
+                requests.push(index);
+            }
+        }
+        requests
+    }
+
+    /// How many times chunk `index` has been requested
+    pub fn attempts(&self, index: usize) -> u32 {
+        self.attempts[index]
+    }
+
+    pub fn is_in_flight(&self, index: usize) -> bool {
+        self.states[index] == ChunkState::InFlight
+    }
+
+    /// The earliest time a failed chunk is due to be requested again
+    pub fn next_retry_at(&self) -> Option<Instant> {
+        self.states
+            .iter()
+            .filter_map(|state| match state {
+                ChunkState::Backoff(retry_at) => Some(*retry_at),
+                _ => None,
+            })
+            .min()
+    }
+
+    pub fn mark_bad_peer(&mut self, peer: PeerId) {
+        self.bad_peers.insert(peer);
+    }
+
+    pub fn is_bad_peer(&self, peer: &PeerId) -> bool {
+        self.bad_peers.contains(peer)
+    }
+
+    /// Record a failed fetch of chunk `index`. Returns false once the chunk has used up its attempts.
+    pub fn chunk_failed(&mut self, index: usize) -> bool {
+        // An erasure coded stripe does not need this shard if a spare one can take its place
+        let (width, _) = layout(&self.manifest);
+        let stripe_start = index - index % width;
+        if let Some(spare) = self.states[stripe_start..stripe_start + width]
+            .iter()
+            .position(|state| *state == ChunkState::Spare)
+        {
+            self.states[stripe_start + spare] = ChunkState::Pending;
+            self.states[index] = ChunkState::Dropped;
+            return true;
+        }
+
+        let attempts = self.attempts[index];
+        if attempts >= MAX_CHUNK_ATTEMPTS {
+            return false;
+        }
+        let backoff = (BASE_BACKOFF * 2u32.pow(attempts.saturating_sub(1))).min(MAX_BACKOFF);
+        self.states[index] = ChunkState::Backoff(Instant::now() + backoff);
+        true
+    }
+
+    /// Take a verified chunk. A replicated chunk is written at its offset in the output file
+    /// right away; an erasure coded shard is buffered until its stripe can be rebuilt.
+    /// Encrypted chunks are decrypted first.
+    pub fn chunk_received(&mut self, index: usize, chunk: &[u8]) -> io::Result<()> {
+        let decrypted;
+        let chunk = match &self.file_key {
+            Some(key) => {
+                decrypted = crypto::decrypt_chunk(key, index, chunk).map_err(io::Error::other)?;
+                decrypted.as_slice()
+            }
+            None => chunk,
+        };
+        self.states[index] = ChunkState::Done;
+
+        match self.manifest.redundancy {
+            Redundancy::Replicated => {
+                let offset = index as u64 * self.manifest.chunk_size as u64;
+                self.write_at(offset, chunk)?;
+                self.unit_done(index);
+            }
+            Redundancy::ErasureCoded { data_shards, parity_shards } => {
+                let (width, data) = layout(&self.manifest);
+                let stripe = index / width;
+                let shards = self.stripes.entry(stripe).or_insert_with(|| vec![None; width]);
+                shards[index % width] = Some(chunk.to_vec());
+                if shards.iter().filter(|shard| shard.is_some()).count() < data {
+                    return Ok(());
+                }
+
+                let shards = self.stripes.remove(&stripe).unwrap_or_default();
+                let mut content = erasure::decode_stripe(shards, data_shards as usize, parity_shards as usize)
+                    .map_err(io::Error::other)?;
+                // The last stripe is padded, cut it back to the file size
+                let offset = (stripe * data) as u64 * self.manifest.chunk_size as u64;
+                content.truncate((self.manifest.size - offset) as usize);
+                self.write_at(offset, &content)?;
+
+                // The stripe's other shards are no longer needed
+                for state in &mut self.states[stripe * width..(stripe + 1) * width] {
+                    if *state != ChunkState::Done {
+                        *state = ChunkState::Dropped;
+                    }
+                }
+                self.unit_done(stripe);
+            }
+        }
+        self.save_progress()
+    }
+
+    fn write_at(&self, offset: u64, bytes: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).open(&self.file_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(bytes)
+    }
+
+    fn unit_done(&mut self, unit: usize) {
+        if !self.units_done[unit] {
+            self.units_done[unit] = true;
+            self.completed += 1;
+        }
+    }
+
+    /// Check the finished file against the size and content hash in the manifest,
+    /// and drop the progress sidecar once it is confirmed
+    pub fn finish(&self) -> Result<(), String> {
+        let written = std::fs::read(&self.file_path).map_err(|e| e.to_string())?;
+        if written.len() as u64 != self.manifest.size {
+            return Err(format!(
+                "expected {} bytes, got {} bytes",
+                self.manifest.size,
+                written.len()
+            ));
+        }
+        if crypto::content_hash(self.file_key.as_ref(), &written) != self.manifest.content_hash {
+            return Err("content hash mismatch".to_string());
+        }
+        let _ = std::fs::remove_file(&self.progress_path);
+        Ok(())
+    }
+}
+
+// Chunks per unit and how many of them are needed to rebuild it
+fn layout(manifest: &FileManifest) -> (usize, usize) {
+    match manifest.redundancy {
+        Redundancy::Replicated => (1, 1),
+        Redundancy::ErasureCoded { data_shards, parity_shards } => {
+            ((data_shards + parity_shards) as usize, data_shards as usize)
+        }
+    }
+}
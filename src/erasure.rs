@@ -0,0 +1,41 @@
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+/// Split `content` into stripes of `data_shards * shard_size` bytes and add `parity_shards`
+/// Reed–Solomon parity shards to each stripe. The last stripe is padded with zeros.
+///
+/// Returns every shard of every stripe in order: the data shards of stripe 0, its parity
+/// shards, then stripe 1, and so on. Any `data_shards` shards of a stripe are enough to
+/// rebuild it.
+pub fn encode(content: &[u8], data_shards: usize, parity_shards: usize, shard_size: usize) -> Result<Vec<Vec<u8>>, String> {
+    let codec = ReedSolomon::new(data_shards, parity_shards).map_err(|e| format!("{:?}", e))?;
+    let mut shards = Vec::new();
+
+    for stripe in content.chunks(data_shards * shard_size) {
+        let mut stripe_shards: Vec<Vec<u8>> = (0..data_shards + parity_shards)
+            .map(|i| {
+                let start = (i * shard_size).min(stripe.len());
+                let end = ((i + 1) * shard_size).min(stripe.len());
+                let mut shard = if i < data_shards { stripe[start..end].to_vec() } else { Vec::new() };
+                shard.resize(shard_size, 0);
+                shard
+            })
+            .collect();
+        codec.encode(&mut stripe_shards).map_err(|e| format!("{:?}", e))?;
+        shards.extend(stripe_shards);
+    }
+
+    Ok(shards)
+}
+
+/// Rebuild the data of one stripe from whichever of its shards were retrieved.
+/// Needs at least `data_shards` of them; the result still carries the zero padding.
+pub fn decode_stripe(mut shards: Vec<Option<Vec<u8>>>, data_shards: usize, parity_shards: usize) -> Result<Vec<u8>, String> {
+    let codec = ReedSolomon::new(data_shards, parity_shards).map_err(|e| format!("{:?}", e))?;
+    codec.reconstruct_data(&mut shards).map_err(|e| format!("{:?}", e))?;
+
+    Ok(shards
+        .into_iter()
+        .take(data_shards)
+        .flat_map(|shard| shard.unwrap_or_default())
+        .collect())
+}
@@ -0,0 +1,193 @@
+use crate::crypto::{self, FileKey, WrappedKey};
+use crate::erasure;
+use crate::keys::RecordKey;
+use libp2p::kad::K_VALUE;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Size in bytes of each file chunk stored in the DHT.
+/// Kademlia messages are capped at 16 KiB, so a chunk has to stay well below that.
+pub const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Version of the manifest format written by this build
+pub const MANIFEST_VERSION: u8 = 3;
+
+/// BLAKE3 hash of a chunk, which is also what the chunk is stored under in the DHT.
+/// Identical chunks map to the same key, so they are only stored once across all files.
+pub fn chunk_hash(chunk: &[u8]) -> [u8; 32] {
+    *blake3::hash(chunk).as_bytes()
+}
+
+/// How a file's chunks protect against lost peers
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Redundancy {
+    // Chunks are plain slices of the file, each stored on several peers
+    Replicated,
+    // The file is cut into stripes of `data_shards` chunks, each stripe extended with
+    // `parity_shards` Reed–Solomon shards. Any `data_shards` shards rebuild the stripe.
+    ErasureCoded { data_shards: u8, parity_shards: u8 },
+}
+
+impl Redundancy {
+    /// Parse an erasure coding spec written as `<data>+<parity>`, e.g. `4+2`.
+    /// A stripe has at most K_VALUE shards: each one is put on a different peer close to
+    /// its key, and a lookup only asks the K_VALUE closest peers.
+    pub fn parse_erasure(spec: &str) -> Option<Self> {
+        let (data, parity) = spec.split_once('+')?;
+        let data_shards: u8 = data.parse().ok()?;
+        let parity_shards: u8 = parity.parse().ok()?;
+        Some(Redundancy::ErasureCoded { data_shards, parity_shards }).filter(Redundancy::is_valid)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Redundancy::Replicated => true,
+            Redundancy::ErasureCoded { data_shards, parity_shards } => {
+                data_shards > 0 && parity_shards > 0 && data_shards as usize + parity_shards as usize <= K_VALUE.get()
+            }
+        }
+    }
+}
+
+/// Manifest stored under the file key describing an uploaded file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileManifest {
+    // Format version, bumped whenever the fields change
+    pub version: u8,
+    // Original file name, used as the name of the downloaded file
+    pub name: String,
+    // Size of the original file in bytes
+    pub size: u64,
+    // Size of every chunk but the last one (of every shard when erasure coded)
+    pub chunk_size: u32,
+    // How the chunks are laid out, see Redundancy
+    pub redundancy: Redundancy,
+    // Hashes of the chunks in order, stripe by stripe when erasure coded
+    pub chunks: Vec<[u8; 32]>,
+    // BLAKE3 hash of the whole file, keyed with the file key when encrypted
+    pub content_hash: [u8; 32],
+    // Set when the chunks are encrypted: the file key wrapped for every user allowed to read it
+    pub wrapped_keys: Option<Vec<WrappedKey>>,
+    // ed25519 public key of the user who uploaded the file
+    pub owner: Vec<u8>,
+    // Upload time in seconds since the unix epoch
+    pub created_at: u64,
+}
+
+/// Name the file at `file_path` is stored under in its manifest: the last component of
+/// the path, or `file_key` if it has none
+pub fn stored_name(file_path: &str, file_key: &str) -> String {
+    Path::new(file_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| file_key.to_string())
+}
+
+impl FileManifest {
+    /// Split `content` into the chunks to store, CHUNK_SIZE bytes each, and build the
+    /// manifest listing them. Returns the chunks in manifest order.
+    ///
+    /// With `recipients`, every chunk is encrypted under a fresh file key and that key is
+    /// wrapped for each of the given ed25519 public keys.
+    ///
+    /// `created_at` is the timestamp of the put-file request, whose signature covers the
+    /// encoded manifest: an unencrypted file signed with `kv-client sign` is rebuilt by the
+    /// node from the same file into the very same manifest.
+    pub fn build(
+        name: String,
+        content: &[u8],
+        owner: Vec<u8>,
+        redundancy: Redundancy,
+        recipients: Option<&[Vec<u8>]>,
+        created_at: u64,
+    ) -> Result<(Self, Vec<Vec<u8>>), String> {
+        let mut chunks: Vec<Vec<u8>> = match redundancy {
+            Redundancy::Replicated => content.chunks(CHUNK_SIZE).map(|chunk| chunk.to_vec()).collect(),
+            Redundancy::ErasureCoded { data_shards, parity_shards } => {
+                erasure::encode(content, data_shards as usize, parity_shards as usize, CHUNK_SIZE)?
+            }
+        };
+
+        let mut file_key: Option<FileKey> = None;
+        let mut wrapped_keys = None;
+        if let Some(recipients) = recipients {
+            let key = crypto::generate_file_key();
+            for (index, chunk) in chunks.iter_mut().enumerate() {
+                *chunk = crypto::encrypt_chunk(&key, index, chunk)?;
+            }
+            wrapped_keys = Some(
+                recipients
+                    .iter()
+                    .map(|recipient| crypto::wrap_key(&key, recipient))
+                    .collect::<Result<Vec<_>, _>>()?,
+            );
+            file_key = Some(key);
+        }
+
+        let manifest = FileManifest {
+            version: MANIFEST_VERSION,
+            name,
+            size: content.len() as u64,
+            chunk_size: CHUNK_SIZE as u32,
+            redundancy,
+            chunks: chunks.iter().map(|chunk| chunk_hash(chunk)).collect(),
+            content_hash: crypto::content_hash(file_key.as_ref(), content),
+            wrapped_keys,
+            owner,
+            created_at,
+        };
+        Ok((manifest, chunks))
+    }
+
+    /// DHT key chunk `index` is stored under, a shard key when erasure coded
+    pub fn chunk_key(&self, index: usize) -> RecordKey {
+        match self.redundancy {
+            Redundancy::Replicated => RecordKey::Chunk(self.chunks[index]),
+            Redundancy::ErasureCoded { .. } => RecordKey::Shard(self.chunks[index]),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Failed to serialize file manifest")
+    }
+
+    /// Decode a manifest, rejecting formats this build does not understand and manifests
+    /// whose chunk list does not match the size, chunk size and redundancy they state
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        // The version is the first byte, check it before trying to read the rest
+        if bytes.first() != Some(&MANIFEST_VERSION) {
+            return None;
+        }
+        let manifest: FileManifest = bincode::deserialize(bytes).ok()?;
+        if manifest.chunk_size == 0 || manifest.chunk_size as usize > CHUNK_SIZE || !manifest.redundancy.is_valid() {
+            return None;
+        }
+        if manifest.expected_chunks() != Some(manifest.chunks.len() as u64) {
+            return None;
+        }
+        Some(manifest)
+    }
+
+    // Number of chunks a file of `size` bytes is split into, `None` if it overflows
+    fn expected_chunks(&self) -> Option<u64> {
+        let chunk_size = self.chunk_size as u64;
+        match self.redundancy {
+            Redundancy::Replicated => Some(self.size.div_ceil(chunk_size)),
+            Redundancy::ErasureCoded { data_shards, parity_shards } => {
+                let stripes = self.size.div_ceil(chunk_size * data_shards as u64);
+                stripes.checked_mul(data_shards as u64 + parity_shards as u64)
+            }
+        }
+    }
+
+    /// Name to write the downloaded file under. Only the last path component is kept
+    /// so a crafted name cannot write outside the current directory.
+    pub fn local_file_name(&self, file_key: &str) -> String {
+        Path::new(&self.name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| !name.is_empty())
+            .unwrap_or(file_key)
+            .to_string()
+    }
+}
@@ -0,0 +1,145 @@
+use scrypt::Params;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+// Directory the private keys of the users registered on this machine are kept in
+const KEY_DIR: &str = "./private_keys";
+
+// Written at the start of every encrypted key file, older files hold the raw key
+const MAGIC: &[u8] = b"kvkey1";
+
+/// A private key encrypted under a passphrase, as saved on disk
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    // scrypt costs the passphrase was stretched with, kept so they can be raised later
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    // The ed25519 secret key encrypted with ChaCha20-Poly1305 under the stretched passphrase
+    ciphertext: Vec<u8>,
+}
+
+/// Where the private key of `username` is kept
+pub fn path(username: &str) -> PathBuf {
+    PathBuf::from(KEY_DIR).join(format!("{}.private_key", username))
+}
+
+pub fn exists(username: &str) -> bool {
+    path(username).exists()
+}
+
+/// Encrypt `secret_key` under `passphrase` and save it for `username`, readable by the
+/// current OS user only
+pub fn write(username: &str, secret_key: &[u8], passphrase: &str) -> Result<(), String> {
+    create_key_dir()?;
+    write_to(&path(username), secret_key, passphrase)
+}
+
+/// Encrypt `secret_key` under `passphrase` into the file at `path`, such as a backup made
+/// by export-key. The file is readable by the current OS user only.
+pub fn write_to(path: &Path, secret_key: &[u8], passphrase: &str) -> Result<(), String> {
+    if passphrase.is_empty() {
+        return Err("The passphrase may not be empty".to_string());
+    }
+
+    let params = Params::recommended();
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+
+    let key = stretch(passphrase, &salt, &params)?;
+    let ciphertext = ChaCha20Poly1305::new(&Key::from(key))
+        .encrypt(&Nonce::from(nonce), secret_key)
+        .map_err(|_| "Failed to encrypt private key".to_string())?;
+    let key_file = KeyFile {
+        log_n: params.log_n(),
+        r: params.r(),
+        p: params.p(),
+        salt,
+        nonce,
+        ciphertext,
+    };
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(bincode::serialize(&key_file).map_err(|e| e.to_string())?);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // The mode only applies to new files, an existing one is tightened first
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(|e| e.to_string())?;
+        }
+    }
+    let mut file = options.open(path).map_err(|e| e.to_string())?;
+    file.write_all(&bytes).map_err(|e| e.to_string())?;
+    file.sync_all().map_err(|e| e.to_string())
+}
+
+/// Load and decrypt the private key of `username`
+pub fn read(username: &str, passphrase: &str) -> Result<Vec<u8>, String> {
+    let bytes = match fs::read(path(username)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err("This client is not registered".to_string());
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    decrypt(username, &bytes, passphrase)
+}
+
+/// Whether `bytes` hold a private key encrypted by `write`, rather than a raw key
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Decrypt the contents of a key file, `name` is only used in warnings
+pub fn decrypt(name: &str, bytes: &[u8], passphrase: &str) -> Result<Vec<u8>, String> {
+    let key_file: KeyFile = match bytes.strip_prefix(MAGIC) {
+        Some(encoded) => bincode::deserialize(encoded).map_err(|_| "Corrupted private key file".to_string())?,
+        // Saved before keys were encrypted, still usable until the key is stored again
+        None if bytes.len() == 32 => {
+            eprintln!("Warning: the private key of '{}' is not encrypted", name);
+            return Ok(bytes.to_vec());
+        }
+        None => return Err("Corrupted private key file".to_string()),
+    };
+
+    let params = Params::new(key_file.log_n, key_file.r, key_file.p)
+        .map_err(|e| format!("Corrupted private key file: {}", e))?;
+    let key = stretch(passphrase, &key_file.salt, &params)?;
+    ChaCha20Poly1305::new(&Key::from(key))
+        .decrypt(&Nonce::from(key_file.nonce), key_file.ciphertext.as_slice())
+        .map_err(|_| "Wrong passphrase".to_string())
+}
+
+// Derive the key encrypting a private key from the passphrase with scrypt
+fn stretch(passphrase: &str, salt: &[u8], params: &Params) -> Result<[u8; 32], String> {
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), salt, params, &mut key)
+        .map_err(|e| format!("Failed to derive key from passphrase: {}", e))?;
+    Ok(key)
+}
+
+fn create_key_dir() -> Result<(), String> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(KEY_DIR).map_err(|e| e.to_string())
+}
@@ -0,0 +1,115 @@
+use libp2p::kad::record::Key;
+use std::fmt;
+
+const VALUE_PREFIX: &str = "kv/";
+const MANIFEST_PREFIX: &str = "manifest/";
+const CHUNK_PREFIX: &str = "chunk/";
+const SHARD_PREFIX: &str = "shard/";
+const USER_PREFIX: &str = "user/";
+const GRANT_PREFIX: &str = "grant/";
+const OWNER_PREFIX: &str = "owner/";
+const MEMBER_PREFIX: &str = "member/";
+
+/// What a DHT record key refers to.
+///
+/// Every key is stored with a prefix naming its kind, and keys typed by users always
+/// get the `kv/` prefix. A user key therefore can never collide with a manifest or a
+/// chunk key, whatever characters it contains.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordKey {
+    // A plain key-value pair stored with `put`
+    Value(String),
+    // The manifest of a file stored with `put -f`
+    Manifest(String),
+    // A file chunk, addressed by the BLAKE3 hash of its content
+    Chunk([u8; 32]),
+    // A shard of an erasure coded file, addressed by the BLAKE3 hash of its content.
+    // Unlike a chunk it is stored on a single peer and never replicated.
+    Shard([u8; 32]),
+    // A registered user, by public key
+    User(Vec<u8>),
+    // Permissions of a user (hex public key) or group (`group:<name>`) on a key or prefix rule
+    Grant { key: String, subject: String },
+    // The owner of a key, the user who wrote it first
+    Owner(String),
+    // Whether the user with `public_key` is a member of `group`
+    Member { group: String, public_key: Vec<u8> },
+}
+
+impl RecordKey {
+    /// Encode into the key used in the DHT
+    pub fn to_key(&self) -> Key {
+        let encoded = match self {
+            RecordKey::Value(key) => format!("{}{}", VALUE_PREFIX, key),
+            RecordKey::Manifest(file_key) => format!("{}{}", MANIFEST_PREFIX, file_key),
+            RecordKey::Chunk(hash) => format!("{}{}", CHUNK_PREFIX, hex::encode(hash)),
+            RecordKey::Shard(hash) => format!("{}{}", SHARD_PREFIX, hex::encode(hash)),
+            RecordKey::User(public_key) => format!("{}{}", USER_PREFIX, hex::encode(public_key)),
+            // The subject comes first as it never contains a '/', the key may
+            RecordKey::Grant { key, subject } => format!("{}{}/{}", GRANT_PREFIX, subject, key),
+            RecordKey::Owner(key) => format!("{}{}", OWNER_PREFIX, key),
+            RecordKey::Member { group, public_key } => {
+                format!("{}{}/{}", MEMBER_PREFIX, hex::encode(public_key), group)
+            }
+        };
+        Key::new(&encoded)
+    }
+
+    /// Whether `key` is the key of a shard, without decoding it
+    pub fn is_shard(key: &Key) -> bool {
+        key.as_ref().starts_with(SHARD_PREFIX.as_bytes())
+    }
+
+    /// Decode a key from the DHT, `None` if it was not written by this program
+    pub fn parse(key: &Key) -> Option<Self> {
+        let key = std::str::from_utf8(key.as_ref()).ok()?;
+        if let Some(key) = key.strip_prefix(VALUE_PREFIX) {
+            Some(RecordKey::Value(key.to_string()))
+        } else if let Some(file_key) = key.strip_prefix(MANIFEST_PREFIX) {
+            Some(RecordKey::Manifest(file_key.to_string()))
+        } else if let Some(hash) = key.strip_prefix(CHUNK_PREFIX) {
+            let mut bytes = [0u8; 32];
+            hex::decode_to_slice(hash, &mut bytes).ok()?;
+            Some(RecordKey::Chunk(bytes))
+        } else if let Some(hash) = key.strip_prefix(SHARD_PREFIX) {
+            let mut bytes = [0u8; 32];
+            hex::decode_to_slice(hash, &mut bytes).ok()?;
+            Some(RecordKey::Shard(bytes))
+        } else if let Some(public_key) = key.strip_prefix(USER_PREFIX) {
+            Some(RecordKey::User(hex::decode(public_key).ok()?))
+        } else if let Some(key) = key.strip_prefix(OWNER_PREFIX) {
+            Some(RecordKey::Owner(key.to_string()))
+        } else if let Some(grant) = key.strip_prefix(GRANT_PREFIX) {
+            let (subject, key) = grant.split_once('/')?;
+            Some(RecordKey::Grant {
+                key: key.to_string(),
+                subject: subject.to_string(),
+            })
+        } else if let Some(member) = key.strip_prefix(MEMBER_PREFIX) {
+            let (public_key, group) = member.split_once('/')?;
+            Some(RecordKey::Member {
+                group: group.to_string(),
+                public_key: hex::decode(public_key).ok()?,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for RecordKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordKey::Value(key) => write!(f, "{}", key),
+            RecordKey::Manifest(file_key) => write!(f, "manifest of '{}'", file_key),
+            RecordKey::Chunk(hash) => write!(f, "chunk {}", hex::encode(hash)),
+            RecordKey::Shard(hash) => write!(f, "shard {}", hex::encode(hash)),
+            RecordKey::User(public_key) => write!(f, "user {}", hex::encode(public_key)),
+            RecordKey::Grant { key, subject } => write!(f, "permission on '{}' for {}", key, subject),
+            RecordKey::Owner(key) => write!(f, "owner of '{}'", key),
+            RecordKey::Member { group, public_key } => {
+                write!(f, "membership of {} in group '{}'", hex::encode(public_key), group)
+            }
+        }
+    }
+}
@@ -0,0 +1,13 @@
+// Modules shared by the node and `kv-client`: what a client needs to hold keys, sign
+// requests, encrypt, split and reassemble files and talk to a node. Only libp2p's record
+// key and peer id types are used here, never its network stack.
+pub mod access;
+pub mod api;
+pub mod client;
+pub mod crypto;
+pub mod download;
+pub mod erasure;
+pub mod file;
+pub mod keyfile;
+pub mod keys;
+pub mod request;
@@ -1,12 +1,17 @@
 mod node;
 mod behaviour;
-mod client;
+mod peers;
+mod server;
+use kv_storage::{access, api, download, file, keys, request};
 use clap::{Arg, Command};
 use async_std::io::{self, BufReader};
 use async_std::prelude::*;
 use futures::StreamExt;
-use node::Node;
-use hex;
+use futures::channel::mpsc;
+use node::{Node, NodeConfig};
+use node::identity::KeyType;
+use file::Redundancy;
+use node::auth::{Permissions, Role, Subject};
 use std::io::{Write};
 
 #[async_std::main]
@@ -25,15 +30,101 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help("Start listening on a specific address")
                 .default_value("/ip4/0.0.0.0/tcp/0")
         )
+        .arg(
+            Arg::new("data-dir")
+                .short('d')
+                .long("data-dir")
+                .value_name("DIR")
+                .help("Directory where records are persisted between runs (in-memory only if omitted)")
+        )
+        .arg(
+            Arg::new("parallel")
+                .short('p')
+                .long("parallel")
+                .value_name("N")
+                .help("Number of file chunks fetched at the same time (default 16)")
+                .value_parser(clap::value_parser!(usize))
+        )
+        .arg(
+            Arg::new("api")
+                .short('a')
+                .long("api")
+                .value_name("ADDRESS")
+                .help("Accept kv-client requests on this address, such as 127.0.0.1:7400 (off if omitted)")
+        )
+        .arg(
+            Arg::new("identity")
+                .short('i')
+                .long("identity")
+                .value_name("FILE")
+                .help("File the node's key pair is loaded from, or saved to on first use (default: identity.key in the data directory)")
+        )
+        .arg(
+            Arg::new("key-type")
+                .short('k')
+                .long("key-type")
+                .value_name("TYPE")
+                .help("Kind of key pair a new node identity gets, ed25519 or secp256k1 (default secp256k1)")
+                .value_parser(["ed25519", "secp256k1"])
+        )
+        .arg(
+            Arg::new("bootstrap")
+                .short('b')
+                .long("bootstrap")
+                .value_name("MULTIADDR")
+                .help("Peer to join the network through, such as /ip4/10.0.0.5/tcp/4001/p2p/<peer id> (repeatable)")
+                .action(clap::ArgAction::Append)
+                .value_parser(clap::value_parser!(libp2p::Multiaddr))
+        )
+        .arg(
+            Arg::new("admin-key")
+                .long("admin-key")
+                .value_name("PUBLIC_KEY")
+                .help("Public key (hex) of a user trusted as admin without being appointed, such as the network's first admin (repeatable)")
+                .action(clap::ArgAction::Append)
+                .value_parser(parse_public_key)
+        )
+        .arg(
+            Arg::new("no-mdns")
+                .long("no-mdns")
+                .help("Do not look for peers on the local network with mDNS, only use --bootstrap and dial")
+                .action(clap::ArgAction::SetTrue)
+        )
         .get_matches();
 
     // Create a new node
-    let mut node = Node::new().await;
+    let config = NodeConfig {
+        data_dir: matches.get_one::<String>("data-dir").map(|dir| dir.into()),
+        download_window: matches
+            .get_one::<usize>("parallel")
+            .copied()
+            .unwrap_or(download::DEFAULT_DOWNLOAD_WINDOW),
+        identity: matches.get_one::<String>("identity").map(|file| file.into()),
+        key_type: matches
+            .get_one::<String>("key-type")
+            .and_then(|key_type| KeyType::parse(key_type)),
+        mdns: !matches.get_flag("no-mdns"),
+        admin_keys: matches.get_many::<Vec<u8>>("admin-key").into_iter().flatten().cloned().collect(),
+    };
+    let mut node = Node::new(config).await;
 
     // Start listening on the specified or default address
     let listen_addr = matches.get_one::<String>("listen").unwrap();
     node.start_listening(listen_addr);
 
+    // Join the network through the configured peers, for networks mDNS does not reach
+    for addr in matches.get_many::<libp2p::Multiaddr>("bootstrap").into_iter().flatten() {
+        if let Err(e) = node.dial(addr.clone()) {
+            eprintln!("Failed to dial bootstrap peer {}: {}", addr, e);
+        }
+    }
+
+    // Requests from kv-client arrive on their own tasks and are carried out in the main loop
+    let (api_calls, mut api_requests) = mpsc::unbounded::<server::ApiCall>();
+    if let Some(api_addr) = matches.get_one::<String>("api") {
+        async_std::task::spawn(server::serve(api_addr.clone(), api_calls.clone()));
+    }
+
     println!("Distributed Hash Table (DHT) Storage System");
     println!("Node initialized with PeerId: {}", node.peer_id);
     println!("Type 'help' for available commands");
@@ -46,47 +137,60 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         print!("p2p> ");
         std::io::stdout().flush()?; // make sure the prompt is displayed immediately
 
+        // Wakes the loop up when a failed chunk is due to be fetched again
+        let retry_delay = node.swarm.behaviour().next_retry_in();
+        let retry_timer = futures::FutureExt::fuse(async {
+            match retry_delay {
+                Some(delay) => async_std::task::sleep(delay).await,
+                None => futures::future::pending::<()>().await,
+            }
+        });
+        futures::pin_mut!(retry_timer);
+
         futures::select! {
             // Handle user input
             line = stdin.next() => {
                 match line {
                     Some(Ok(input)) => {
                         // Trim and split input
-                        let parts: Vec<&str> = input.trim().split_whitespace().collect();
+                        let parts: Vec<&str> = input.split_whitespace().collect();
                         
                         // Process command
                         match parts.as_slice() {
                             ["help"] => {
                                 println!("Available commands:");
-                                println!("  register <username> [--admin]        - Register a new user");
                                 println!("  put <key> <value> <pk> <sig>         - Store a key-value pair");
-                                println!("  put -f <key> <file_path> <pk> <sig>  - Store a file");
+                                println!("  put -f <key> <file_path> <pk> <sig> [--erasure <k>+<m>]");
+                                println!("                                       - Store a file, optionally erasure coded, signed with");
+                                println!("                                         kv-client sign <user> put-file <key> <file_path> [--erasure ..]");
+                                println!("                                         (kv-client put-file stores encrypted files)");
                                 println!("  get <key> <pk> <sig>                 - Retrieve a key-value pair");
-                                println!("  get -f <key> <pk> <sig>              - Retrieve a file");
-                                println!("  sign <username> <key>                - Sign a file");
-                                println!("  permission <key> <pk>                - Permit access to a file");
+                                println!("  get -f <key> <pk> <sig>              - Retrieve a file into this directory, unless encrypted");
+                                println!("  delete <key> <pk> <sig>              - Delete the value and file stored under a key");
+                                println!("  Requests are signed with kv-client sign, which also registers users");
+                                println!("  permission <key> <subject> <perms> <granter_pk> <granter_sig>");
+                                println!("                                       - Give a user (pk) or group (group:<name>) permissions");
+                                println!("                                         such as read,write or all on a key, or on every key");
+                                println!("                                         under a prefix with <prefix>* (admins, the owner or");
+                                println!("                                         users with grant)");
+                                println!("  revoke <key> <subject> <signer_pk> <signer_sig>");
+                                println!("                                       - Withdraw permissions on a key (owner or admin)");
+                                println!("  acl <key> <signer_pk> <signer_sig>   - List who is permitted on a key (owner or admin)");
+                                println!("  acl --user <pk> <signer_pk> <signer_sig>");
+                                println!("                                       - List the keys a user is permitted on (owner or admin)");
+                                println!("  role <pk> <admin|writer|reader> <admin_pk> <admin_sig>");
+                                println!("                                       - Change the role of a user (admins only)");
+                                println!("  group add|remove <group> <pk> <admin_pk> <admin_sig>");
+                                println!("                                       - Add a user to or remove them from a group (admins only)");
+                                println!("  audit [<key>]                        - Show the audit log, or its entries for a key, and check its chain");
+                                println!("  users [<username>]                   - List the users, or one user, with their keys and roles");
+                                println!("  downloads                            - Show the progress of file downloads");
                                 println!("  listen <address>                     - Start listening on an address");
+                                println!("  dial <multiaddr>                     - Connect to a peer and fill the routing table from it");
+                                println!("  peers                                - Show which peers are connected or were seen lately");
                                 println!("  help                                 - Print this help message");
                                 println!("  exit                                 - Exit the program gracefully");
                             }
-                            ["register", username, flag] if *flag == "--admin" => {
-                                match node.user_manager.register_user(username, true) {
-                                    Ok(public_key) => {
-                                        println!("Registered admin user: {}", username);
-                                        println!("Public Key (hex): {}", hex::encode(&public_key));
-                                    }
-                                    Err(e) => println!("Registration failed: {}", e),
-                                }
-                            }
-                            ["register", username] => {
-                                match node.user_manager.register_user(username, false) {
-                                    Ok(public_key) => {
-                                        println!("Registered user: {}", username);
-                                        println!("Public Key (hex): {}", hex::encode(&public_key));
-                                    }
-                                    Err(e) => println!("Registration failed: {}", e),
-                                }
-                            }
                             ["put", key, value, public_key, signature] => {
                                 let pk_bytes = match hex::decode(public_key) {
                                     Ok(pk) => pk,
@@ -131,7 +235,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                     println!("Retrieval failed");
                                 }
                             },
-                            ["put", "-f", key, file_path, public_key, signature] => {
+                            ["put", "-f", key, file_path, public_key, signature, options @ ..] => {
+                                let mut redundancy = Redundancy::Replicated;
+                                let mut options = options.iter();
+                                let mut valid = true;
+                                while let Some(option) = options.next() {
+                                    match *option {
+                                        "--erasure" => match options.next().and_then(|spec| Redundancy::parse_erasure(spec)) {
+                                            Some(parsed) => redundancy = parsed,
+                                            None => {
+                                                println!("Invalid erasure spec, expected <data>+<parity> such as 4+2");
+                                                valid = false;
+                                                break;
+                                            }
+                                        },
+                                        _ => {
+                                            println!("Invalid command. Type 'help' for available commands.");
+                                            valid = false;
+                                            break;
+                                        }
+                                    }
+                                }
+                                if !valid {
+                                    continue;
+                                }
                                 let pk_bytes = match hex::decode(public_key) {
                                     Ok(pk) => pk,
                                     Err(_) => {
@@ -147,7 +274,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                     }
                                 };
                                 
-                                if node.put_file(key.to_string(), file_path.to_string(), pk_bytes, sig_bytes) {
+                                if node.put_file(key.to_string(), file_path.to_string(), pk_bytes, sig_bytes, redundancy) {
                                     println!("Successfully stored file: {}", file_path);
                                 } else {
                                     println!("File storage operation failed");
@@ -168,14 +295,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                         continue;
                                     }
                                 };
-                                
+                                // Encrypted files need the user's private key, they are retrieved with kv-client get-file
                                 if node.get_file(key.to_string(), pk_bytes, sig_bytes) {
                                     println!("Retrieving file with key: {}", key);
                                 } else {
                                     println!("File retrieval failed");
                                 }
                             },
-                            ["permission", key, public_key] => {
+                            ["delete", key, public_key, signature] => {
                                 let pk_bytes = match hex::decode(public_key) {
                                     Ok(pk) => pk,
                                     Err(_) => {
@@ -183,27 +310,212 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                         continue;
                                     }
                                 };
+                                let sig_bytes = match hex::decode(signature) {
+                                    Ok(sig) => sig,
+                                    Err(_) => {
+                                        println!("Invalid signature format");
+                                        continue;
+                                    }
+                                };
+
+                                if !node.delete(key.to_string(), pk_bytes, sig_bytes) {
+                                    println!("Delete failed");
+                                }
+                            },
+                            ["permission", key, subject, permissions, granter_public_key, granter_signature] => {
+                                let permissions = match Permissions::parse(permissions) {
+                                    Some(permissions) => permissions,
+                                    None => {
+                                        println!("Invalid permissions, expected a list such as read,write,delete,grant or all");
+                                        continue;
+                                    }
+                                };
+                                let subject = match Subject::parse(subject) {
+                                    Some(subject) => subject,
+                                    None => {
+                                        println!("Invalid subject, expected a public key or group:<name>");
+                                        continue;
+                                    }
+                                };
+                                let (granter_pk_bytes, granter_sig_bytes) = match (hex::decode(granter_public_key), hex::decode(granter_signature)) {
+                                    (Ok(pk), Ok(sig)) => (pk, sig),
+                                    _ => {
+                                        println!("Invalid granter public key or signature format");
+                                        continue;
+                                    }
+                                };
                                 
-                                match node.user_manager.add_key_permission(key, &pk_bytes) {
-                                    Ok(_) => println!("Permission granted for key: {}", key),
+                                match node.grant_permission(key, &subject, permissions, granter_pk_bytes, granter_sig_bytes) {
+                                    Ok(_) => println!("Granted {} on key: {}", permissions, key),
                                     Err(e) => println!("Failed to grant permission: {}", e),
                                 }
                             },
-                            ["sign", username, message] => {
-                                match client::sign_message(username, message) {
-                                    Ok((public_key, signature, _)) => {
-                                        println!("Public Key (hex): {}", hex::encode(&public_key));
-                                        println!("Signature (hex): {}", hex::encode(&signature));
+                            ["revoke", key, subject, signer_public_key, signer_signature] => {
+                                let subject = match Subject::parse(subject) {
+                                    Some(subject) => subject,
+                                    None => {
+                                        println!("Invalid subject, expected a public key or group:<name>");
+                                        continue;
                                     }
-                                    Err(e) => {
-                                        println!("Signing failed: {}", e);
+                                };
+                                let (signer_pk_bytes, signer_sig_bytes) = match (hex::decode(signer_public_key), hex::decode(signer_signature)) {
+                                    (Ok(pk), Ok(sig)) => (pk, sig),
+                                    _ => {
+                                        println!("Invalid signer public key or signature format");
+                                        continue;
                                     }
+                                };
+
+                                match node.revoke_permission(key, &subject, signer_pk_bytes, signer_sig_bytes) {
+                                    Ok(true) => println!("Permissions revoked for key: {}", key),
+                                    Ok(false) => println!("{} had no permissions on key: {}", subject, key),
+                                    Err(e) => println!("Failed to revoke permissions: {}", e),
+                                }
+                            },
+                            ["acl", "--user", public_key, signer_public_key, signer_signature] => {
+                                let pk_bytes = match hex::decode(public_key) {
+                                    Ok(pk) => pk,
+                                    Err(_) => {
+                                        println!("Invalid public key format");
+                                        continue;
+                                    }
+                                };
+                                let (signer_pk_bytes, signer_sig_bytes) = match (hex::decode(signer_public_key), hex::decode(signer_signature)) {
+                                    (Ok(pk), Ok(sig)) => (pk, sig),
+                                    _ => {
+                                        println!("Invalid signer public key or signature format");
+                                        continue;
+                                    }
+                                };
+
+                                match node.user_acl(&pk_bytes, &signer_pk_bytes, &signer_sig_bytes) {
+                                    Ok(permissions) if permissions.is_empty() => println!("No permissions for user {}", public_key),
+                                    Ok(permissions) => {
+                                        for (key, permissions) in permissions {
+                                            println!("{}: {}", key, permissions);
+                                        }
+                                    }
+                                    Err(e) => println!("Failed to list permissions: {}", e),
+                                }
+                            },
+                            ["acl", key, signer_public_key, signer_signature] => {
+                                let (signer_pk_bytes, signer_sig_bytes) = match (hex::decode(signer_public_key), hex::decode(signer_signature)) {
+                                    (Ok(pk), Ok(sig)) => (pk, sig),
+                                    _ => {
+                                        println!("Invalid signer public key or signature format");
+                                        continue;
+                                    }
+                                };
+
+                                match node.key_acl(key, &signer_pk_bytes, &signer_sig_bytes) {
+                                    Ok(permissions) => {
+                                        match node.user_manager.owner(key) {
+                                            Some(owner) => println!("owner {}", hex::encode(owner)),
+                                            None => println!("no owner"),
+                                        }
+                                        for (subject, permissions) in permissions {
+                                            println!("{}: {}", subject, permissions);
+                                        }
+                                    }
+                                    Err(e) => println!("Failed to list permissions: {}", e),
+                                }
+                            },
+                            ["role", public_key, role, admin_public_key, admin_signature] => {
+                                let role = match Role::parse(role) {
+                                    Some(role) => role,
+                                    None => {
+                                        println!("Unknown role, expected admin, writer or reader");
+                                        continue;
+                                    }
+                                };
+                                let pk_bytes = match hex::decode(public_key) {
+                                    Ok(pk) => pk,
+                                    Err(_) => {
+                                        println!("Invalid public key format");
+                                        continue;
+                                    }
+                                };
+                                let (admin_pk_bytes, admin_sig_bytes) = match (hex::decode(admin_public_key), hex::decode(admin_signature)) {
+                                    (Ok(pk), Ok(sig)) => (pk, sig),
+                                    _ => {
+                                        println!("Invalid admin public key or signature format");
+                                        continue;
+                                    }
+                                };
+
+                                match node.set_role(&pk_bytes, role, admin_pk_bytes, admin_sig_bytes) {
+                                    Ok(_) => println!("User {} is now {}", public_key, role.as_str()),
+                                    Err(e) => println!("Failed to change role: {}", e),
+                                }
+                            },
+                            ["group", action, group, public_key, admin_public_key, admin_signature] if *action == "add" || *action == "remove" => {
+                                let pk_bytes = match hex::decode(public_key) {
+                                    Ok(pk) => pk,
+                                    Err(_) => {
+                                        println!("Invalid public key format");
+                                        continue;
+                                    }
+                                };
+                                let (admin_pk_bytes, admin_sig_bytes) = match (hex::decode(admin_public_key), hex::decode(admin_signature)) {
+                                    (Ok(pk), Ok(sig)) => (pk, sig),
+                                    _ => {
+                                        println!("Invalid admin public key or signature format");
+                                        continue;
+                                    }
+                                };
+
+                                let member = *action == "add";
+                                match node.set_group_member(group, &pk_bytes, member, admin_pk_bytes, admin_sig_bytes) {
+                                    Ok(_) if member => println!("Added {} to group {}", public_key, group),
+                                    Ok(_) => println!("Removed {} from group {}", public_key, group),
+                                    Err(e) => println!("Failed to change group: {}", e),
                                 }
                             },
                             ["listen", addr] => {
                                 node.start_listening(addr);
                                 println!("Listening on: {}", addr);
                             },
+                            ["audit", key @ ..] if key.len() <= 1 => {
+                                for line in node.audit.lines(key.first().copied()) {
+                                    println!("  {}", line);
+                                }
+                            },
+                            ["dial", addr] => {
+                                match addr.parse::<libp2p::Multiaddr>() {
+                                    Ok(addr) => match node.dial(addr.clone()) {
+                                        Ok(()) => println!("Dialing {}", addr),
+                                        Err(e) => println!("Failed to dial {}: {}", addr, e),
+                                    },
+                                    Err(_) => println!("Invalid multiaddr"),
+                                }
+                            },
+                            ["peers"] => {
+                                let status = node.swarm.behaviour().peers.status();
+                                if status.is_empty() {
+                                    println!("No peers");
+                                }
+                                for line in status {
+                                    println!("  {}", line);
+                                }
+                            },
+                            ["users", username @ ..] if username.len() <= 1 => {
+                                let lines = server::user_lines(&node, username.first().copied());
+                                if lines.is_empty() {
+                                    println!("No users");
+                                }
+                                for line in lines {
+                                    println!("  {}", line);
+                                }
+                            },
+                            ["downloads"] => {
+                                let status = node.swarm.behaviour().download_status();
+                                if status.is_empty() {
+                                    println!("No downloads");
+                                }
+                                for line in status {
+                                    println!("  {}", line);
+                                }
+                            },
                             ["exit"] => break,
                             _ => {
                                 println!("Invalid command. Type 'help' for available commands.");
@@ -221,6 +533,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             },
 
+            // Carry out requests from kv-client
+            call = api_requests.next() => {
+                if let Some((request, respond)) = call {
+                    server::handle(&mut node, request, respond, &api_calls);
+                }
+            },
+
+            // Retry chunks whose backoff is over
+            _ = retry_timer => {
+                node.swarm.behaviour_mut().retry_due_chunks();
+            },
+
             // Handle swarm events
             /*
             swarm events include 1) network-level 2) behaviour-specific
@@ -241,11 +565,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         libp2p::swarm::SwarmEvent::NewListenAddr { address, .. } => {
                             println!("Listening on: {:?}", address);
                         }
-                        libp2p::swarm::SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                        libp2p::swarm::SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
                             println!("Connected to peer: {:?}", peer_id);
+                            node.on_connected(&peer_id, &endpoint);
+                            let republished = node.republish_records();
+                            if republished > 0 {
+                                println!("Republishing {} records loaded from disk", republished);
+                            }
                         }
                         libp2p::swarm::SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
                             println!("Disconnected from peer: {:?}, cause: {:?}", peer_id, cause);
+                            node.on_disconnected(&peer_id);
+                        }
+                        libp2p::swarm::SwarmEvent::UnreachableAddr { peer_id, attempts_remaining: 0, .. } => {
+                            node.on_dial_failed(&peer_id);
                         }
                         _ => {}
                     },
@@ -261,3 +594,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Exiting the File Storage System");
     Ok(())
 }
+
+// An ed25519 public key given in hex on the command line
+fn parse_public_key(hex_key: &str) -> Result<Vec<u8>, String> {
+    let public_key = hex::decode(hex_key).map_err(|e| e.to_string())?;
+    if public_key.len() != 32 {
+        return Err("expected an ed25519 public key, 64 hex characters".to_string());
+    }
+    Ok(public_key)
+}
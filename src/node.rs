@@ -1,54 +1,551 @@
 use libp2p::{
-    PeerId, Swarm,
-    kad::{Kademlia, record::{Key, Record, store::MemoryStore}, Quorum},
+    Multiaddr, PeerId, Swarm,
+    core::{multiaddr::Protocol, ConnectedPoint},
+    kad::{kbucket, Kademlia, KademliaConfig, record::{Key, Record, store::{self as record_store, RecordStore}}, Quorum},
     mdns::Mdns,
-    development_transport, identity,
+    development_transport,
 };
 use crate::behaviour::Behaviour;
+use crate::file::{self, chunk_hash, FileManifest, Redundancy};
+use crate::keys::RecordKey;
+use crate::request::{self, Operation, RequestSignature};
+use futures::channel::oneshot;
+use std::collections::{HashMap, HashSet};
+use std::iter;
 use std::num::NonZeroUsize;
-mod auth;
-use crate::node::auth::UserManager;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+pub mod audit;
+pub mod auth;
+pub mod identity;
+pub mod store;
+use crate::node::audit::AuditLog;
+use crate::node::identity::KeyType;
+use crate::node::auth::{self as access, Approval, Grant, KeyRotation, Membership, Ownership, Permissions, Role, SignedWrite, Subject, UserManager};
+use crate::node::store::DiskStore;
+
+/// How long a user, ownership or permission record looked up on the network is not looked
+/// up again, found or not
+const ACCESS_LOOKUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Settings chosen on the command line when starting a node
+pub struct NodeConfig {
+    // Directory where the node keeps its records between runs, records only live in memory when None
+    pub data_dir: Option<PathBuf>,
+    // How many chunks of a file are fetched at the same time
+    pub download_window: usize,
+    // File the node's key pair is kept in, instead of `identity.key` in the data directory
+    pub identity: Option<PathBuf>,
+    // Kind of key pair to generate, and to expect in the identity file when set
+    pub key_type: Option<KeyType>,
+    // Whether peers on the local network are found with mDNS
+    pub mdns: bool,
+    // Public keys of the users trusted as admins without being appointed, see `UserManager::set_trusted_admins`
+    pub admin_keys: Vec<Vec<u8>>,
+}
 
 pub struct Node {
     pub peer_id: PeerId,
-    pub swarm: Swarm<Behaviour>, // The main swarm managing networking
+    pub swarm: Swarm<Behaviour<DiskStore>>, // The main swarm managing networking
     pub user_manager: auth::UserManager,
+    // Every authenticated request and how it ended
+    pub audit: AuditLog,
+    // Records loaded from disk still need to be pushed to the network once a peer shows up
+    pending_republish: bool,
+    // Addresses dialed without a peer id, their peer joins the routing table once connected
+    pending_dials: HashSet<Multiaddr>,
+    // When each access record was last looked up on the network, see `look_up_access_records`
+    access_lookups: HashMap<Key, Instant>,
 }
 
 impl Node {
     /// Create a new node
-    pub async fn new() -> Self {
-        // Generate identity
-        let keypair = identity::Keypair::generate_secp256k1();
+    pub async fn new(config: NodeConfig) -> Self {
+        // Load the identity kept from a previous run, so the PeerId and with it the node's place
+        // in the Kademlia keyspace stay the same. Without a data directory it is generated anew.
+        let identity_path = config
+            .identity
+            .clone()
+            .or_else(|| config.data_dir.as_ref().map(|data_dir| data_dir.join("identity.key")));
+        let keypair = match &identity_path {
+            Some(path) => identity::load_or_create(path, config.key_type).expect("Failed to load node identity"),
+            None => identity::generate(config.key_type.unwrap_or(KeyType::Secp256k1)),
+        };
         let peer_id = PeerId::from(keypair.public());
-        println!("Generated PeerId: {:?}", peer_id);
+        match &identity_path {
+            Some(path) => println!("PeerId: {:?} (identity kept in {})", peer_id, path.display()),
+            None => println!("Generated PeerId: {:?}", peer_id),
+        }
 
         // Set up transport
         let transport = development_transport(keypair.clone())
             .await
             .expect("Failed to create transport");
 
-        // Create Kademlia
-        let store = MemoryStore::new(peer_id.clone());
-        let kademlia = Kademlia::new(peer_id.clone(), store);
+        // Initialize UserManager, keeping users and permissions next to the records
+        let mut user_manager = match &config.data_dir {
+            Some(data_dir) => UserManager::open(&data_dir.join("users.bin"))
+                .expect("Failed to load users"),
+            None => UserManager::new(),
+        };
+        user_manager.set_trusted_admins(config.admin_keys.clone());
 
-        // Set up mDNS： used to find nodes on the same network
-        let mdns = Mdns::new(Default::default())
-            .await
-            .expect("Failed to initialize mDNS");
+        let audit = match &config.data_dir {
+            Some(data_dir) => AuditLog::open(&data_dir.join("audit.log"))
+                .expect("Failed to load audit log"),
+            None => AuditLog::in_memory(),
+        };
+        if let Err(index) = audit.verify() {
+            eprintln!("Audit log chain is broken at entry {}, entries were changed or removed", index + 1);
+        }
+
+        // Create Kademlia, backed by records persisted in the data directory if there is one
+        let mut store = match &config.data_dir {
+            Some(data_dir) => DiskStore::new(peer_id, data_dir)
+                .expect("Failed to open record store"),
+            None => DiskStore::in_memory(peer_id),
+        };
+        let pending_republish = store.records().next().is_some();
+        // Users and permissions published by other nodes are verified as they arrive
+        // and added to the UserManager, so they can be used on this node as well
+        for record in store.records() {
+            user_manager.apply_record(&record);
+        }
+        let validating_user_manager = user_manager.clone();
+        // A value or manifest only replaces the one stored if it was written later
+        store.set_validator(Box::new(move |record, stored| {
+            if !validating_user_manager.apply_record(record) {
+                return Err("it does not verify, or its signer may not make it".to_string());
+            }
+            if !access::supersedes(record, stored) {
+                return Err("the write stored under its key has a higher sequence number or another owner".to_string());
+            }
+            Ok(())
+        }));
+        let mut kademlia_config = KademliaConfig::default();
+        // Manifests of large files list many chunk hashes, so allow messages
+        // big enough for any record the store accepts (16 KiB by default)
+        kademlia_config.set_max_packet_size(128 * 1024);
+        let kademlia = Kademlia::with_config(peer_id, store, kademlia_config);
+
+        // Set up mDNS： used to find nodes on the same network, unless turned off
+        // where multicast does not work and peers are dialed instead (see `dial`)
+        let mdns = if config.mdns {
+            Some(Mdns::new(Default::default()).await.expect("Failed to initialize mDNS"))
+        } else {
+            None
+        };
 
         // Combine behaviours
-        // in behaviour.rs, struct Behaviour has 2 attributes
-        let behaviour = Behaviour { kademlia, mdns};
+        // in behaviour.rs, struct Behaviour has 2 network behaviours and the download state
+        let behaviour = Behaviour {
+            kademlia,
+            mdns: mdns.into(),
+            downloads: HashMap::new(),
+            pending_chunks: HashMap::new(),
+            download_window: config.download_window.max(1),
+            download_outcomes: HashMap::new(),
+            record_waiters: HashMap::new(),
+            sequence_waiters: HashMap::new(),
+            peers: Default::default(),
+        };
 
         // Create swarm
-        let swarm = Swarm::new(transport, behaviour, peer_id.clone());
+        let swarm = Swarm::new(transport, behaviour, peer_id);
+
+        Node {
+            peer_id,
+            swarm,
+            user_manager,
+            audit,
+            pending_republish,
+            pending_dials: HashSet::new(),
+            access_lookups: HashMap::new(),
+        }
+    }
+
+    /// Register a user whose key pair was made by `kv-client` and publish the user to the rest
+    /// of the network. `signature` is the user's signed `register` request for the username.
+    pub fn register_user(&mut self, username: &str, public_key: &[u8], signature: &[u8], is_admin: bool) -> Result<(), String> {
+        let result = self.try_register_user(username, public_key, signature, is_admin);
+        self.audited(public_key, Operation::Register, username, result)
+    }
+
+    fn try_register_user(&mut self, username: &str, public_key: &[u8], signature: &[u8], is_admin: bool) -> Result<(), String> {
+        self.user_manager.register_user(username, public_key, signature, is_admin)?;
+        if let Some(user) = self.user_manager.user(public_key) {
+            let value = bincode::serialize(&user).map_err(|e| e.to_string())?;
+            self.publish_access_record(RecordKey::User(public_key.to_vec()), value);
+        }
+        Ok(())
+    }
+
+    /// Give a user or group permissions on a key or prefix rule, on this node and on every
+    /// node the grant reaches. Needs a signed `grant` request for the key, the subject and the
+    /// permissions, from an admin, the key's owner or a user granted the grant permission on it.
+    pub fn grant_permission(&mut self, key: &str, subject: &Subject, permissions: Permissions, granter_key: Vec<u8>, granter_signature: Vec<u8>) -> Result<(), String> {
+        let signer = granter_key.clone();
+        let result = self.try_grant_permission(key, subject, permissions, granter_key, granter_signature);
+        self.audited(&signer, Operation::Grant, key, result)
+    }
+
+    fn try_grant_permission(&mut self, key: &str, subject: &Subject, permissions: Permissions, granter_key: Vec<u8>, granter_signature: Vec<u8>) -> Result<(), String> {
+        let content_hash = access::grant_content_hash(subject, permissions);
+        self.user_manager
+            .authenticate_request(&granter_key, &granter_signature, Operation::Grant, key, Some(&content_hash))?;
+        if !self.user_manager.check_access(key, &granter_key, Operation::Grant) {
+            return Err("Only an admin, the owner or a user with the grant permission can grant on this key".to_string());
+        }
+
+        let grant = Grant {
+            key: key.to_string(),
+            subject: subject.clone(),
+            permissions,
+            approval: Approval { signer: granter_key, signature: granter_signature },
+        };
+        self.user_manager.add_key_permission(grant.clone())?;
+        let value = bincode::serialize(&grant).map_err(|e| e.to_string())?;
+        self.publish_access_record(
+            RecordKey::Grant { key: key.to_string(), subject: subject.to_string() },
+            value,
+        );
+        Ok(())
+    }
+
+    /// Withdraw every permission of a user or group on a key or prefix rule, on this node and
+    /// on every node the revocation reaches. Needs a signed `revoke` request for the key and the
+    /// subject, from the key's owner or an admin. Returns whether the subject had any permission.
+    pub fn revoke_permission(&mut self, key: &str, subject: &Subject, signer_key: Vec<u8>, signer_signature: Vec<u8>) -> Result<bool, String> {
+        let signer = signer_key.clone();
+        let result = self.try_revoke_permission(key, subject, signer_key, signer_signature);
+        self.audited(&signer, Operation::Revoke, key, result)
+    }
+
+    fn try_revoke_permission(&mut self, key: &str, subject: &Subject, signer_key: Vec<u8>, signer_signature: Vec<u8>) -> Result<bool, String> {
+        let content_hash = access::revoke_content_hash(subject);
+        self.user_manager
+            .authenticate_request(&signer_key, &signer_signature, Operation::Revoke, key, Some(&content_hash))?;
+        if !self.user_manager.can_manage(key, &signer_key) {
+            return Err("Only the owner of the key or an admin can revoke permissions on it".to_string());
+        }
+
+        let revocation = Grant {
+            key: key.to_string(),
+            subject: subject.clone(),
+            permissions: Permissions::default(),
+            approval: Approval { signer: signer_key, signature: signer_signature },
+        };
+        let had_permissions = self.user_manager.revoke_key_permission(revocation.clone())?;
+        let value = bincode::serialize(&revocation).map_err(|e| e.to_string())?;
+        self.publish_access_record(
+            RecordKey::Grant { key: key.to_string(), subject: subject.to_string() },
+            value,
+        );
+        Ok(had_permissions)
+    }
+
+    /// Users and groups permitted on a key or prefix rule and what they may do, as known to
+    /// this node. Needs a signed `acl` request for the key from the key's owner or an admin.
+    pub fn key_acl(&mut self, key: &str, signer_key: &[u8], signer_signature: &[u8]) -> Result<Vec<(Subject, Permissions)>, String> {
+        let result = self.try_key_acl(key, signer_key, signer_signature);
+        self.audited(signer_key, Operation::ListAcl, key, result)
+    }
+
+    fn try_key_acl(&self, key: &str, signer_key: &[u8], signer_signature: &[u8]) -> Result<Vec<(Subject, Permissions)>, String> {
+        self.user_manager
+            .authenticate_request(signer_key, signer_signature, Operation::ListAcl, key, None)?;
+        if !self.user_manager.can_manage(key, signer_key) {
+            return Err("Only the owner of the key or an admin can list permissions on it".to_string());
+        }
+        Ok(self.user_manager.key_permissions(key))
+    }
+
+    /// Keys a user owns or is permitted on, as known to this node. Needs a signed `acl-user`
+    /// request for the user's hex public key. Admins see every key, owners only the keys they own.
+    pub fn user_acl(&mut self, public_key: &[u8], signer_key: &[u8], signer_signature: &[u8]) -> Result<Vec<(String, Permissions)>, String> {
+        let result = self.try_user_acl(public_key, signer_key, signer_signature);
+        self.audited(signer_key, Operation::ListUserAcl, &hex::encode(public_key), result)
+    }
+
+    fn try_user_acl(&self, public_key: &[u8], signer_key: &[u8], signer_signature: &[u8]) -> Result<Vec<(String, Permissions)>, String> {
+        self.user_manager
+            .authenticate_request(signer_key, signer_signature, Operation::ListUserAcl, &hex::encode(public_key), None)?;
+        Ok(self
+            .user_manager
+            .user_permissions(public_key)
+            .into_iter()
+            .filter(|(key, _)| self.user_manager.can_manage(key, signer_key))
+            .collect())
+    }
+
+    /// Add a user to a group (`member`) or remove them from it, on this node and on every node
+    /// the change reaches. Needs an admin's signed `group-add` or `group-remove` request for
+    /// the group name and the user's public key.
+    pub fn set_group_member(&mut self, group: &str, public_key: &[u8], member: bool, admin_key: Vec<u8>, admin_signature: Vec<u8>) -> Result<(), String> {
+        let signer = admin_key.clone();
+        let op = if member { Operation::GroupAdd } else { Operation::GroupRemove };
+        let result = self.try_set_group_member(group, public_key, member, admin_key, admin_signature);
+        self.audited(&signer, op, group, result)
+    }
+
+    fn try_set_group_member(&mut self, group: &str, public_key: &[u8], member: bool, admin_key: Vec<u8>, admin_signature: Vec<u8>) -> Result<(), String> {
+        if !access::is_valid_group_name(group) {
+            return Err("Group names may not be empty or contain '/'".to_string());
+        }
+        let op = if member { Operation::GroupAdd } else { Operation::GroupRemove };
+        let content_hash = request::content_hash(public_key);
+        let request = self.user_manager
+            .authorize_admin(&admin_key, &admin_signature, op, group, Some(&content_hash))?;
+
+        self.user_manager.set_group_member(group, public_key, member, request.timestamp)?;
+        let membership = Membership {
+            group: group.to_string(),
+            public_key: public_key.to_vec(),
+            member,
+            approval: Approval { signer: admin_key, signature: admin_signature },
+        };
+        let value = bincode::serialize(&membership).map_err(|e| e.to_string())?;
+        self.publish_access_record(
+            RecordKey::Member { group: group.to_string(), public_key: public_key.to_vec() },
+            value,
+        );
+        Ok(())
+    }
 
-        // Initialize UserManager
-        let user_manager = UserManager::new();
+    /// Give a registered user a role. Needs an admin's signed `role` request for the
+    /// user's hex public key and the role name.
+    pub fn set_role(&mut self, public_key: &[u8], role: Role, admin_key: Vec<u8>, admin_signature: Vec<u8>) -> Result<(), String> {
+        let signer = admin_key.clone();
+        let result = self.try_set_role(public_key, role, admin_key, admin_signature);
+        self.audited(&signer, Operation::SetRole, &hex::encode(public_key), result)
+    }
+
+    fn try_set_role(&mut self, public_key: &[u8], role: Role, admin_key: Vec<u8>, admin_signature: Vec<u8>) -> Result<(), String> {
+        let content_hash = request::content_hash(role.as_str().as_bytes());
+        self.user_manager.authorize_admin(
+            &admin_key,
+            &admin_signature,
+            Operation::SetRole,
+            &hex::encode(public_key),
+            Some(&content_hash),
+        )?;
+
+        let approval = Approval { signer: admin_key, signature: admin_signature };
+        self.user_manager.set_role(public_key, role, approval)?;
+        if let Some(user) = self.user_manager.user(public_key) {
+            let value = bincode::serialize(&user).map_err(|e| e.to_string())?;
+            self.publish_access_record(RecordKey::User(public_key.to_vec()), value);
+        }
+        Ok(())
+    }
+
+    /// Replace a user's key pair with a new one, on this node and on every node the change
+    /// reaches. Needs the user's signed `rotate-key` request for the user's hex public key and
+    /// the new public key, made with the old key, and the new key's signature over the registration.
+    pub fn rotate_key(&mut self, old_key: Vec<u8>, signature: Vec<u8>, new_key: Vec<u8>, new_signature: Vec<u8>) -> Result<(), String> {
+        let signer = old_key.clone();
+        let result = self.try_rotate_key(old_key, signature, new_key, new_signature);
+        self.audited(&signer, Operation::RotateKey, &hex::encode(&signer), result)
+    }
+
+    fn try_rotate_key(&mut self, old_key: Vec<u8>, signature: Vec<u8>, new_key: Vec<u8>, new_signature: Vec<u8>) -> Result<(), String> {
+        let content_hash = request::content_hash(&new_key);
+        self.user_manager.authenticate_request(
+            &old_key,
+            &signature,
+            Operation::RotateKey,
+            &hex::encode(&old_key),
+            Some(&content_hash),
+        )?;
+
+        let rotation = KeyRotation { old_key: old_key.clone(), new_key: new_key.clone(), signature, new_signature };
+        self.user_manager.rotate_key(rotation)?;
+        // The old key's record carries the rotation to the other nodes
+        for public_key in [old_key, new_key] {
+            if let Some(user) = self.user_manager.user(&public_key) {
+                let value = bincode::serialize(&user).map_err(|e| e.to_string())?;
+                self.publish_access_record(RecordKey::User(public_key), value);
+            }
+        }
+        Ok(())
+    }
+
+    /// The audit log, or only its entries for `key`, one per line. Needs an admin's signed
+    /// `audit` request for the key, or for an empty key to see every entry.
+    pub fn audit_lines(&mut self, key: Option<&str>, admin_key: &[u8], admin_signature: &[u8]) -> Result<Vec<String>, String> {
+        let signed_key = key.unwrap_or("");
+        let result = self.user_manager
+            .authorize_admin(admin_key, admin_signature, Operation::Audit, signed_key, None)
+            .map(|_| ());
+        self.audited(admin_key, Operation::Audit, signed_key, result)?;
+        Ok(self.audit.lines(key))
+    }
+
+    // The first user to write a key becomes its owner, with every permission on it.
+    // The signed write request is kept as proof so other nodes can check the claim.
+    fn claim_ownership(&mut self, key: &str, owner: &[u8], op: Operation, content_hash: [u8; 32], signature: &[u8]) {
+        if self.user_manager.owner(key).is_some() {
+            return;
+        }
+        let ownership = Ownership {
+            key: key.to_string(),
+            owner: owner.to_vec(),
+            op: op.as_str().to_string(),
+            content_hash,
+            signature: signature.to_vec(),
+        };
+        match self.user_manager.claim_ownership(ownership.clone()) {
+            Ok(true) => {
+                let value = bincode::serialize(&ownership).expect("Failed to serialize ownership");
+                self.publish_access_record(RecordKey::Owner(key.to_string()), value);
+            }
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to record the owner of '{}': {}", key, e),
+        }
+    }
+
+    // Why the local store refused a record put on this node: the validator's reason, or the
+    // store's own error, such as a full store
+    fn store_error(&mut self, error: record_store::Error) -> String {
+        match self.swarm.behaviour_mut().kademlia.store_mut().take_refusal() {
+            Some(reason) => reason,
+            None => format!("{:?}", error),
+        }
+    }
+
+    // Note a request in the audit log and pass on how it ended
+    fn audited<T>(&mut self, public_key: &[u8], op: Operation, key: &str, result: Result<T, String>) -> Result<T, String> {
+        let outcome = result.as_ref().map(|_| ()).map_err(|e| e.clone());
+        self.audit.record(public_key, op, key, outcome);
+        result
+    }
+
+    fn publish_access_record(&mut self, key: RecordKey, value: Vec<u8>) {
+        let record = Record {
+            key: key.to_key(),
+            value,
+            publisher: None,
+            expires: None,
+        };
+        // Kademlia stores it on the closest peers whatever the quorum, one is enough to report success
+        if let Err(e) = self.swarm.behaviour_mut().kademlia.put_record(record, Quorum::One) {
+            eprintln!("Failed to publish {}: {:?}", key, e);
+        }
+    }
+
+    /// Look up on the network the user, ownership and permission records a request by
+    /// `public_key` on `key` may need but this node does not have, as they are only stored
+    /// on the peers closest to their key. Returns a receiver per lookup, told once it completes;
+    /// the records found are verified and taken into account as they are stored. A record is
+    /// looked up at most once per ACCESS_LOOKUP_INTERVAL, whether it was found or not.
+    pub fn look_up_access_records(&mut self, public_key: &[u8], key: Option<&str>) -> Vec<oneshot::Receiver<Result<Vec<u8>, String>>> {
+        let mut missing = Vec::new();
+        if self.user_manager.user(public_key).is_none() {
+            missing.push(RecordKey::User(public_key.to_vec()));
+        }
+        if let Some(key) = key {
+            if self.user_manager.owner(key).is_none() {
+                missing.push(RecordKey::Owner(key.to_string()));
+            }
+            let subject = Subject::User(public_key.to_vec());
+            if !self.user_manager.key_permissions(key).iter().any(|(granted, _)| *granted == subject) {
+                missing.push(RecordKey::Grant { key: key.to_string(), subject: subject.to_string() });
+            }
+        }
+
+        let now = Instant::now();
+        self.access_lookups
+            .retain(|_, looked_up_at| now.duration_since(*looked_up_at) < ACCESS_LOOKUP_INTERVAL);
+        let mut lookups = Vec::new();
+        for record_key in missing {
+            let dht_key = record_key.to_key();
+            if self.access_lookups.contains_key(&dht_key) {
+                continue;
+            }
+            self.access_lookups.insert(dht_key.clone(), now);
+            lookups.push(self.wait_for_record(&record_key));
+            self.swarm.behaviour_mut().kademlia.get_record(&dht_key, Quorum::One);
+        }
+        lookups
+    }
+
+    /// Push the records loaded from disk back into the DHT.
+    /// Only runs once, the first time it is called after a peer connects.
+    pub fn republish_records(&mut self) -> usize {
+        if !self.pending_republish {
+            return 0;
+        }
+        self.pending_republish = false;
+
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        let records: Vec<Record> = kademlia
+            .store_mut()
+            .records()
+            .map(|record| record.into_owned())
+            .collect();
+        let quorum = Quorum::N(NonZeroUsize::new(3).expect("Quorum value must be non-zero"));
+
+        for record in &records {
+            if let Err(e) = kademlia.put_record(record.clone(), quorum) {
+                eprintln!("Failed to republish record: {:?}", e);
+            }
+        }
+
+        records.len()
+    }
+    /// Connect to a peer mDNS cannot find, such as one on another network or in a container.
+    /// An address ending in `/p2p/<peer id>` goes straight into the routing table, any other
+    /// one is dialed and its peer added once connected (see `on_connected`). The routing table
+    /// is then filled in from the new peer.
+    pub fn dial(&mut self, mut addr: Multiaddr) -> Result<(), String> {
+        if let Some(Protocol::P2p(hash)) = addr.iter().last() {
+            let peer_id = PeerId::from_multihash(hash).map_err(|_| "Invalid peer id in address")?;
+            addr.pop();
+            self.swarm.behaviour_mut().peers.discovered(peer_id);
+            self.swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+            self.bootstrap();
+            return Ok(());
+        }
+        self.swarm.dial_addr(addr.clone()).map_err(|e| format!("{:?}", e))?;
+        self.pending_dials.insert(addr);
+        Ok(())
+    }
+
+    /// Called for every new connection, adds a peer dialed by address to the routing table
+    pub fn on_connected(&mut self, peer_id: &PeerId, endpoint: &ConnectedPoint) {
+        self.swarm.behaviour_mut().peers.connected(*peer_id);
+        if let ConnectedPoint::Dialer { address } = endpoint {
+            if self.pending_dials.remove(address) {
+                self.swarm.behaviour_mut().kademlia.add_address(peer_id, address.clone());
+                self.bootstrap();
+            }
+        }
+    }
 
-        Node { peer_id, swarm, user_manager }
+    /// Called for every closed connection
+    pub fn on_disconnected(&mut self, peer_id: &PeerId) {
+        self.swarm.behaviour_mut().peers.disconnected(*peer_id);
     }
+
+    /// Called when a dial to a peer failed on every address. A peer that keeps failing is taken
+    /// out of the routing table, so queries stop waiting for it; it comes back when it connects
+    /// or is discovered again.
+    pub fn on_dial_failed(&mut self, peer_id: &PeerId) {
+        let behaviour = self.swarm.behaviour_mut();
+        if behaviour.peers.dial_failed(*peer_id) {
+            behaviour.kademlia.remove_peer(peer_id);
+            behaviour.peers.pruned(*peer_id);
+            println!("Removed unreachable peer {} from the routing table", peer_id);
+        }
+    }
+
+    // Look up our own PeerId, which fills the routing table with the peers close to us
+    fn bootstrap(&mut self) {
+        if let Err(e) = self.swarm.behaviour_mut().kademlia.bootstrap() {
+            eprintln!("Failed to bootstrap: {:?}", e);
+        }
+    }
+
     /// Start listening on a specified address
     pub fn start_listening(&mut self, addr: &str) {
         self.swarm
@@ -60,20 +557,35 @@ impl Node {
     /// Store a key-value pair in the DHT
     pub fn put(&mut self, key: String, value: Vec<u8>, public_key: Vec<u8>, signature: Vec<u8>) -> bool {
         // Authentication check
-        if !self.user_manager.authenticate(&public_key, &signature, key.as_bytes()) {
-            eprintln!("Authentication failed for key: {}", key);
+        let content_hash = request::content_hash(&value);
+        if let Err(e) = self.user_manager.authenticate_request(&public_key, &signature, Operation::Put, &key, Some(&content_hash)) {
+            eprintln!("Authentication failed for key: {} ({})", key, e);
+            self.audit.record(&public_key, Operation::Put, &key, Err(format!("authentication failed ({})", e)));
             return false;
         }
 
         // Permission check
-        if !self.user_manager.check_key_permission(&key, &public_key) {
+        if !self.user_manager.check_access(&key, &public_key, Operation::Put) {
             eprintln!("Permission denied for key: {}", key);
+            self.audit.record(&public_key, Operation::Put, &key, Err("permission denied".to_string()));
             return false;
         }
 
+        // Peers storing the value check the signed request themselves before accepting it,
+        // with the writer's user record in case they do not know the writer
+        let user = match self.user_manager.user(&public_key) {
+            Some(user) => user,
+            None => {
+                eprintln!("Unknown writer for key: {}", key);
+                self.audit.record(&public_key, Operation::Put, &key, Err("unknown writer".to_string()));
+                return false;
+            }
+        };
+        let proof = self.user_manager.write_proof(&key, &public_key);
+        let write = SignedWrite { value, writer: public_key.clone(), content_hash, signature: signature.clone(), user, proof };
         let record = Record {
-            key: Key::new(&key),
-            value,
+            key: RecordKey::Value(key.clone()).to_key(),
+            value: write.encode(),
             publisher: None,
             expires: None,
         };
@@ -83,9 +595,15 @@ impl Node {
             .behaviour_mut()
             .kademlia
             .put_record(record.clone(), quorum) {
-            Ok(_) => true,
-            Err(_) => {
-                eprintln!("Failed to store record");
+            Ok(_) => {
+                self.claim_ownership(&key, &public_key, Operation::Put, content_hash, &signature);
+                self.audit.record(&public_key, Operation::Put, &key, Ok(()));
+                true
+            }
+            Err(e) => {
+                let e = self.store_error(e);
+                eprintln!("Failed to store record: {}", e);
+                self.audit.record(&public_key, Operation::Put, &key, Err(format!("failed to store record ({})", e)));
                 false
             }
         }
@@ -94,18 +612,21 @@ impl Node {
     /// Retrieve a value for a given key from the DHT
     pub fn get(&mut self, key: String, public_key: Vec<u8>, signature: Vec<u8>) -> bool {
         // Authentication check
-        if !self.user_manager.authenticate(&public_key, &signature, key.as_bytes()) {
-            eprintln!("Authentication failed for key: {}", key);
+        if let Err(e) = self.user_manager.authenticate_request(&public_key, &signature, Operation::Get, &key, None) {
+            eprintln!("Authentication failed for key: {} ({})", key, e);
+            self.audit.record(&public_key, Operation::Get, &key, Err(format!("authentication failed ({})", e)));
             return false;
         }
 
         // Permission check
-        if !self.user_manager.check_key_permission(&key, &public_key) {
+        if !self.user_manager.check_access(&key, &public_key, Operation::Get) {
             eprintln!("Permission denied for key: {}", key);
+            self.audit.record(&public_key, Operation::Get, &key, Err("permission denied".to_string()));
             return false;
         }
+        self.audit.record(&public_key, Operation::Get, &key, Ok(()));
 
-        let key = libp2p::kad::record::Key::new(&key);
+        let key = RecordKey::Value(key).to_key();
         // triggers Kademlia to initiate a GetRecord or PutRecord query
         // see behaviour.rs, OutboundQueryCompleted 
         // Quorum is an enum in the libp2p::kad module that 
@@ -120,23 +641,64 @@ impl Node {
         true
     }
 
-    /// Store a file in the DHT by splitting it into chunks and storing each chunk separately
-    pub fn put_file(&mut self, file_key: String, file_path: String, public_key: Vec<u8>, signature: Vec<u8>) -> bool {
+    /// Be told the value of `key` once a `get` for it completes
+    pub fn wait_for_record(&mut self, key: &RecordKey) -> oneshot::Receiver<Result<Vec<u8>, String>> {
+        let (sender, receiver) = oneshot::channel();
+        self.swarm
+            .behaviour_mut()
+            .record_waiters
+            .entry(key.to_key())
+            .or_default()
+            .push(sender);
+        receiver
+    }
+
+    /// Be told the sequence number of the latest write stored under a value or manifest key,
+    /// looked for on this node and the peers closest to the key (0 if it was never written)
+    pub fn write_sequence(&mut self, record_key: &RecordKey) -> oneshot::Receiver<u64> {
+        let (sender, receiver) = oneshot::channel();
+        let key = record_key.to_key();
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.sequence_waiters.entry(key.clone()).or_default().push(sender);
+        let quorum = Quorum::N(NonZeroUsize::new(3).expect("Quorum value must be non-zero"));
+        behaviour.kademlia.get_record(&key, quorum);
+        receiver
+    }
+
+    /// Delete the value and the file stored under a key from this node.
+    /// Copies held by other nodes are not removed and expire with their record TTL.
+    pub fn delete(&mut self, key: String, public_key: Vec<u8>, signature: Vec<u8>) -> bool {
         // Authentication check
-        if !self.user_manager.authenticate(&public_key, &signature, file_key.as_bytes()) {
-            eprintln!("Authentication failed for file: {}", file_key);
+        if let Err(e) = self.user_manager.authenticate_request(&public_key, &signature, Operation::Delete, &key, None) {
+            eprintln!("Authentication failed for key: {} ({})", key, e);
+            self.audit.record(&public_key, Operation::Delete, &key, Err(format!("authentication failed ({})", e)));
             return false;
         }
 
         // Permission check
-        if !self.user_manager.check_key_permission(&file_key, &public_key) {
-            eprintln!("Permission denied for file: {}", file_key);
+        if !self.user_manager.check_access(&key, &public_key, Operation::Delete) {
+            eprintln!("Permission denied for key: {}", key);
+            self.audit.record(&public_key, Operation::Delete, &key, Err("permission denied".to_string()));
             return false;
         }
+        self.audit.record(&public_key, Operation::Delete, &key, Ok(()));
+
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        kademlia.remove_record(&RecordKey::Value(key.clone()).to_key());
+        kademlia.remove_record(&RecordKey::Manifest(key.clone()).to_key());
+        println!("Deleted '{}' from this node.", key);
 
+        true
+    }
+
+    /// Store a file in the DHT by splitting it into chunks and storing each chunk separately.
+    /// With `Redundancy::ErasureCoded` parity shards are stored instead of extra replicas.
+    /// The signature is made by `kv-client sign put-file`, over the manifest built from the
+    /// same file at the time of signing; encrypted files are stored with `kv-client put-file`.
+    pub fn put_file(&mut self, file_key: String, file_path: String, public_key: Vec<u8>, signature: Vec<u8>, redundancy: Redundancy) -> bool {
         use std::fs;
 
-        let file_content = match fs::read_to_string(&file_path) {
+        let file_content = match fs::read(&file_path) {
             Ok(content) => content,
             Err(e) => {
                 eprintln!("Failed to read file '{}': {:?}", file_path, e);
@@ -144,37 +706,150 @@ impl Node {
             }
         };
 
-        // Split the file content into 500-character chunks
-        let chunks: Vec<String> = file_content
-            .chars()
-            .collect::<Vec<char>>()
-            .chunks(500)
-            .map(|chunk| chunk.iter().collect())
-            .collect();
+        // Built as the signer did: the manifest of an unencrypted file only varies with its
+        // upload time, which is when the request was signed
+        let signed_at = RequestSignature::decode(&signature).map_or(0, |signature| signature.timestamp);
+        let name = file::stored_name(&file_path, &file_key);
+        let (manifest, chunks) = match FileManifest::build(name, &file_content, public_key.clone(), redundancy, None, signed_at) {
+            Ok(built) => built,
+            Err(e) => {
+                eprintln!("Failed to encode file '{}': {}", file_path, e);
+                self.audit.record(&public_key, Operation::PutFile, &file_key, Err(format!("failed to encode file ({})", e)));
+                return false;
+            }
+        };
+        self.put_file_content(file_key, manifest.encode(), chunks, public_key, signature)
+    }
 
-        let total_chunks = chunks.len();
-        
+    /// Store a file a client split into chunks, and encrypted if need be, under `file_key`.
+    /// The put-file request signs the encoded manifest, whose chunk list every chunk has to match.
+    pub fn put_file_content(&mut self, file_key: String, encoded_manifest: Vec<u8>, chunks: Vec<Vec<u8>>, public_key: Vec<u8>, signature: Vec<u8>) -> bool {
+        // Authentication check
+        let content_hash = request::content_hash(&encoded_manifest);
+        if let Err(e) = self.user_manager.authenticate_request(&public_key, &signature, Operation::PutFile, &file_key, Some(&content_hash)) {
+            eprintln!("Authentication failed for file: {} ({})", file_key, e);
+            self.audit.record(&public_key, Operation::PutFile, &file_key, Err(format!("authentication failed ({})", e)));
+            return false;
+        }
 
-        // Store each chunk in the DHT
-        for (counter, chunk) in chunks.iter().enumerate() {
-            let chunk_key = format!("{}_{}_{}", file_key, counter, total_chunks);
+        // Permission check
+        if !self.user_manager.check_access(&file_key, &public_key, Operation::PutFile) {
+            eprintln!("Permission denied for file: {}", file_key);
+            self.audit.record(&public_key, Operation::PutFile, &file_key, Err("permission denied".to_string()));
+            return false;
+        }
+
+        // The manifest lists the hashes of the chunks in order, along with the original name and size
+        let manifest = match FileManifest::decode(&encoded_manifest) {
+            Some(manifest) if manifest.owner == public_key => manifest,
+            Some(_) => {
+                eprintln!("File manifest for '{}' names another uploader", file_key);
+                self.audit.record(&public_key, Operation::PutFile, &file_key, Err("manifest names another uploader".to_string()));
+                return false;
+            }
+            None => {
+                eprintln!("Invalid file manifest for '{}'", file_key);
+                self.audit.record(&public_key, Operation::PutFile, &file_key, Err("invalid file manifest".to_string()));
+                return false;
+            }
+        };
+        let mismatched = chunks.len() != manifest.chunks.len()
+            || chunks.iter().zip(&manifest.chunks).any(|(chunk, hash)| chunk_hash(chunk) != *hash);
+        if mismatched {
+            eprintln!("The chunks of file '{}' do not match its manifest", file_key);
+            self.audit.record(&public_key, Operation::PutFile, &file_key, Err("chunks do not match the manifest".to_string()));
+            return false;
+        }
+        let user = match self.user_manager.user(&public_key) {
+            Some(user) => user,
+            None => {
+                eprintln!("Unknown writer for file: {}", file_key);
+                self.audit.record(&public_key, Operation::PutFile, &file_key, Err("unknown writer".to_string()));
+                return false;
+            }
+        };
+        let name = manifest.name.clone();
+        let redundancy = manifest.redundancy;
+
+        let manifest_record = Record {
+            key: RecordKey::Manifest(file_key.clone()).to_key(),
+            value: SignedWrite {
+                value: encoded_manifest,
+                writer: public_key.clone(),
+                content_hash,
+                signature: signature.clone(),
+                user,
+                proof: self.user_manager.write_proof(&file_key, &public_key),
+            }
+            .encode(),
+            publisher: None,
+            expires: None,
+        };
+        // The manifest lists every chunk hash and has to fit in a single record,
+        // refuse the file before any of its chunks are stored
+        if manifest_record.value.len() >= store::MAX_VALUE_BYTES {
+            let e = format!(
+                "file manifest of {} bytes exceeds the {} byte record limit, the file is too large",
+                manifest_record.value.len(),
+                store::MAX_VALUE_BYTES
+            );
+            eprintln!("Failed to store file '{}': {}", name, e);
+            self.audit.record(&public_key, Operation::PutFile, &file_key, Err(e));
+            return false;
+        }
+
+        // Erasure coded shards already tolerate lost peers, so each one is stored on a single
+        // peer instead of on all the closest peers of its key like a chunk
+        let shard_peers = match redundancy {
+            Redundancy::Replicated => None,
+            Redundancy::ErasureCoded { data_shards, parity_shards } => {
+                match self.shard_peers(&manifest, data_shards as usize + parity_shards as usize) {
+                    Ok(peers) => Some(peers),
+                    Err(e) => {
+                        eprintln!("Failed to store file '{}': {}", name, e);
+                        self.audit.record(&public_key, Operation::PutFile, &file_key, Err(e));
+                        return false;
+                    }
+                }
+            }
+        };
+
+        let total_chunks = manifest.chunks.len();
+        let mut stored = HashSet::new();
+
+        // Store each chunk in the DHT under the hash of its content
+        for (counter, (chunk, hash)) in chunks.into_iter().zip(&manifest.chunks).enumerate() {
+            // Chunks with identical content share a key, store them once
+            if !stored.insert(*hash) {
+                continue;
+            }
+            let chunk_key = manifest.chunk_key(counter);
             let record = Record {
-                key: Key::new(&chunk_key),
-                value: chunk.as_bytes().to_vec(),
+                key: chunk_key.to_key(),
+                value: chunk,
                 publisher: None,
                 expires: None,
             };
-            
-            let quorum = Quorum::N(NonZeroUsize::new(3).expect("Quorum value must be non-zero"));
-
 
-            self.swarm
-                .behaviour_mut()
-                .kademlia
-                .put_record(record, quorum)
-                .expect("Failed to store record");
+            let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+            let put = match &shard_peers {
+                Some(peers) => {
+                    kademlia.put_record_to(record, iter::once(peers[counter]), Quorum::One);
+                    Ok(())
+                }
+                None => {
+                    let quorum = Quorum::N(NonZeroUsize::new(3).expect("Quorum value must be non-zero"));
+                    kademlia.put_record(record, quorum).map(|_| ())
+                }
+            };
+            if let Err(e) = put {
+                let e = self.store_error(e);
+                eprintln!("Failed to store chunk {} of file '{}': {}", counter, file_key, e);
+                self.audit.record(&public_key, Operation::PutFile, &file_key, Err(format!("failed to store chunk {} ({})", counter, e)));
+                return false;
+            }
             println!(
-                "Stored chunk {} of {} for file '{}' under key '{}'.",
+                "Stored chunk {} of {} for file '{}' as {}.",
                 counter + 1,
                 total_chunks,
                 file_key,
@@ -182,54 +857,155 @@ impl Node {
             );
         }
 
-        println!(
-            "Stored file '{}' in {} chunks. Each chunk stored with keys '<file_key>_<chunk_number>_<total_chunks>'.",
-            file_path, total_chunks
-        );
-        let total_chunks_key = format!("{}_total", file_key);
-        let record = Record {
-            key: Key::new(&total_chunks_key),
-            value: total_chunks.to_string().as_bytes().to_vec(),
-            publisher: None,
-            expires: None,
-        };
         let quorum = Quorum::N(NonZeroUsize::new(3).expect("Quorum value must be non-zero"));
 
-        self.swarm
-            .behaviour_mut()
-            .kademlia
-            .put_record(record, quorum)
-            .expect("Failed to store total chunks metadata");
+        if let Err(e) = self.swarm.behaviour_mut().kademlia.put_record(manifest_record, quorum) {
+            let e = self.store_error(e);
+            eprintln!("Failed to store file manifest for '{}': {}", file_key, e);
+            self.audit.record(&public_key, Operation::PutFile, &file_key, Err(format!("failed to store file manifest ({})", e)));
+            return false;
+        }
+        self.claim_ownership(&file_key, &public_key, Operation::PutFile, content_hash, &signature);
+        self.audit.record(&public_key, Operation::PutFile, &file_key, Ok(()));
 
         println!(
-            "Stored file '{}' in {} chunks. Each chunk stored with keys '<file_key>_<chunk_number>_<total_chunks>'. Total chunks metadata stored under key '{}'.",
-            file_path, total_chunks, total_chunks_key
+            "Stored file '{}' ({} bytes) in {} chunks ({} unique). Each chunk stored under the BLAKE3 hash of its content, with the file manifest stored under key '{}'.",
+            name, manifest.size, total_chunks, stored.len(), file_key
         );
+        if let Some(wrapped_keys) = &manifest.wrapped_keys {
+            println!("Chunks encrypted, readable by {} authorized user(s).", wrapped_keys.len());
+        }
+        if let Redundancy::ErasureCoded { data_shards, parity_shards } = redundancy {
+            println!(
+                "Erasure coded with {} data and {} parity shards per stripe, any {} shards of a stripe rebuild it.",
+                data_shards, parity_shards, data_shards
+            );
+        }
 
         true
     }
 
-    /// Retrieve a file from the DHT by reconstructing it from its chunks
+    // The peer each shard of an erasure coded file is put on, in manifest order: the peer in
+    // the routing table closest to the shard's key that holds no other shard of the same
+    // stripe. Losing a peer then costs a stripe at most one shard, and a lookup of the key
+    // still reaches the peer.
+    fn shard_peers(&mut self, manifest: &FileManifest, width: usize) -> Result<Vec<PeerId>, String> {
+        let peers: Vec<kbucket::Key<PeerId>> = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .kbuckets()
+            .flat_map(|bucket| bucket.iter().map(|entry| entry.node.key.clone()).collect::<Vec<_>>())
+            .collect();
+        if peers.len() < width {
+            return Err(format!(
+                "{} shards per stripe need as many peers, only {} known",
+                width,
+                peers.len()
+            ));
+        }
+
+        let mut chosen = Vec::with_capacity(manifest.chunks.len());
+        for (stripe, hashes) in manifest.chunks.chunks(width).enumerate() {
+            let mut used = HashSet::new();
+            for index in stripe * width..stripe * width + hashes.len() {
+                let target = kbucket::Key::new(manifest.chunk_key(index).to_key());
+                let peer = peers
+                    .iter()
+                    .filter(|peer| !used.contains(peer.preimage()))
+                    .min_by_key(|peer| peer.distance(&target))
+                    .map(|peer| *peer.preimage())
+                    .ok_or("not enough peers for a stripe")?;
+                used.insert(peer);
+                chosen.push(peer);
+            }
+        }
+        Ok(chosen)
+    }
+
+    /// Public keys a client is to wrap the file key of an encrypted file stored under `file_key`
+    /// for: everyone currently permitted to read the file, and the writer, who becomes its owner
+    /// if nobody owns it yet. Takes a signed `recipients` request from a user who may write the file.
+    pub fn file_recipients(&mut self, file_key: &str, public_key: &[u8], signature: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        let result = self.try_file_recipients(file_key, public_key, signature);
+        self.audited(public_key, Operation::Recipients, file_key, result)
+    }
+
+    fn try_file_recipients(&mut self, file_key: &str, public_key: &[u8], signature: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        self.user_manager
+            .authenticate_request(public_key, signature, Operation::Recipients, file_key, None)
+            .map_err(|e| format!("Authentication failed ({})", e))?;
+        if !self.user_manager.check_access(file_key, public_key, Operation::Recipients) {
+            return Err("Permission denied".to_string());
+        }
+
+        let mut recipients = self.user_manager.authorized_keys(file_key);
+        if !recipients.iter().any(|recipient| recipient == public_key) {
+            recipients.push(public_key.to_vec());
+        }
+        Ok(recipients)
+    }
+
+    /// Fetch the manifest of a file for a client, which fetches its chunks and unwraps the file
+    /// key of an encrypted file with the user's private key. Takes a signed `get-file` request.
+    pub fn get_file_manifest(&mut self, file_key: &str, public_key: &[u8], signature: &[u8]) -> Result<oneshot::Receiver<Result<Vec<u8>, String>>, String> {
+        let result = self.try_get_file_manifest(file_key, public_key, signature);
+        self.audited(public_key, Operation::GetFile, file_key, result)
+    }
+
+    fn try_get_file_manifest(&mut self, file_key: &str, public_key: &[u8], signature: &[u8]) -> Result<oneshot::Receiver<Result<Vec<u8>, String>>, String> {
+        self.user_manager
+            .authenticate_request(public_key, signature, Operation::GetFile, file_key, None)
+            .map_err(|e| format!("Authentication failed ({})", e))?;
+        if !self.user_manager.check_access(file_key, public_key, Operation::GetFile) {
+            return Err("Permission denied".to_string());
+        }
+
+        let manifest_key = RecordKey::Manifest(file_key.to_string());
+        let receiver = self.wait_for_record(&manifest_key);
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .get_record(&manifest_key.to_key(), libp2p::kad::Quorum::One);
+        Ok(receiver)
+    }
+
+    /// Fetch a chunk, or erasure coded shard, for a client downloading a file itself. Only a
+    /// copy matching `hash` is handed over. Retries ask more peers, as the node's own downloads do.
+    pub fn get_chunk(&mut self, chunk_key: RecordKey, attempt: u32) -> oneshot::Receiver<Result<Vec<u8>, String>> {
+        let receiver = self.wait_for_record(&chunk_key);
+        let quorum = match NonZeroUsize::new(attempt.min(3) as usize) {
+            Some(n) if n.get() > 1 => Quorum::N(n),
+            _ => Quorum::One,
+        };
+        self.swarm.behaviour_mut().kademlia.get_record(&chunk_key.to_key(), quorum);
+        receiver
+    }
+
+    /// Retrieve a file from the DHT by reconstructing it from its chunks, into the node's
+    /// directory. Encrypted files are retrieved with `kv-client get-file`, which decrypts them.
     pub fn get_file(&mut self, file_key: String, public_key: Vec<u8>, signature: Vec<u8>) -> bool {
         // Authentication check
-        if !self.user_manager.authenticate(&public_key, &signature, file_key.as_bytes()) {
-            eprintln!("Authentication failed for file retrieval: {}", file_key);
+        if let Err(e) = self.user_manager.authenticate_request(&public_key, &signature, Operation::GetFile, &file_key, None) {
+            eprintln!("Authentication failed for file retrieval: {} ({})", file_key, e);
+            self.audit.record(&public_key, Operation::GetFile, &file_key, Err(format!("authentication failed ({})", e)));
             return false;
         }
 
         // Permission check
-        if !self.user_manager.check_key_permission(&file_key, &public_key) {
+        if !self.user_manager.check_access(&file_key, &public_key, Operation::GetFile) {
             eprintln!("Permission denied for file retrieval: {}", file_key);
+            self.audit.record(&public_key, Operation::GetFile, &file_key, Err("permission denied".to_string()));
             return false;
         }
+        self.audit.record(&public_key, Operation::GetFile, &file_key, Ok(()));
 
-        let chunk_key = format!("{}_total", file_key);
-        let chunk_key = libp2p::kad::record::Key::new(&chunk_key);
+        let manifest_key = RecordKey::Manifest(file_key.clone()).to_key();
     
         self.swarm
             .behaviour_mut()
             .kademlia
-            .get_record(&chunk_key, libp2p::kad::Quorum::One);
+            .get_record(&manifest_key, libp2p::kad::Quorum::One);
 
         println!(
             "Initiated retrieval for file '{}'",
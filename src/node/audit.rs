@@ -0,0 +1,156 @@
+use crate::request::{self, Operation};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{Cursor, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Append-only record of every authenticated request a node handled: who made it, what
+/// they asked for, when and how it ended. Each entry includes the hash of the one before
+/// it, so changing or removing an entry breaks the chain from there on (see `verify`).
+pub struct AuditLog {
+    // File the entries are appended to, they only live in memory when None
+    path: Option<PathBuf>,
+    entries: Vec<AuditEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    // Unix time in seconds the request was handled at
+    pub timestamp: u64,
+    // Public key the request was signed with
+    pub public_key: Vec<u8>,
+    pub op: String,
+    pub key: String,
+    // Why the request was refused or failed, if it was
+    pub outcome: Result<(), String>,
+    // Hash of the previous entry, all zeroes for the first one
+    pub previous: [u8; 32],
+}
+
+impl AuditEntry {
+    pub fn hash(&self) -> [u8; 32] {
+        request::content_hash(&bincode::serialize(self).expect("Failed to serialize audit entry"))
+    }
+}
+
+impl std::fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let outcome = match &self.outcome {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("failed: {}", e),
+        };
+        write!(f, "{} {} {} '{}' {}", self.timestamp, hex::encode(&self.public_key), self.op, self.key, outcome)
+    }
+}
+
+// Whether an entry could not be read because the log ends in the middle of it
+fn is_cut_short(error: &bincode::Error) -> bool {
+    matches!(&**error, bincode::ErrorKind::Io(e) if e.kind() == ErrorKind::UnexpectedEof)
+}
+
+impl AuditLog {
+    /// A log that is never written to disk, for nodes started without `--data-dir`
+    pub fn in_memory() -> Self {
+        AuditLog { path: None, entries: Vec::new() }
+    }
+
+    /// Load the entries written to `path` by previous runs, or start empty if there is
+    /// no such file yet. New entries are appended to it and continue its chain.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.to_string()),
+        };
+        let mut entries = Vec::new();
+        let mut reader = Cursor::new(&bytes);
+        while (reader.position() as usize) < bytes.len() {
+            let start = reader.position();
+            match bincode::deserialize_from(&mut reader) {
+                Ok(entry) => entries.push(entry),
+                // The node stopped while appending the last entry, which is dropped so that
+                // new entries follow the complete ones
+                Err(e) if is_cut_short(&e) => {
+                    eprintln!(
+                        "Audit log '{}' ends with an incomplete entry ({} bytes), probably from a crash while it was written, dropping it",
+                        path.display(),
+                        bytes.len() as u64 - start
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(path)
+                        .and_then(|file| file.set_len(start))
+                        .map_err(|e| format!("Failed to truncate audit log '{}': {}", path.display(), e))?;
+                    break;
+                }
+                Err(e) => return Err(format!("Corrupted audit log '{}': {}", path.display(), e)),
+            }
+        }
+
+        Ok(AuditLog { path: Some(path.to_path_buf()), entries })
+    }
+
+    /// Add an entry for a request signed with `public_key`. A failed write to disk is
+    /// reported but does not fail the request.
+    pub fn record(&mut self, public_key: &[u8], op: Operation, key: &str, outcome: Result<(), String>) {
+        let entry = AuditEntry {
+            timestamp: request::unix_now(),
+            public_key: public_key.to_vec(),
+            op: op.as_str().to_string(),
+            key: key.to_string(),
+            outcome,
+            previous: self.head(),
+        };
+
+        if let Some(path) = &self.path {
+            let appended = bincode::serialize(&entry)
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| {
+                    let mut file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| e.to_string())?;
+                    file.write_all(&bytes).map_err(|e| e.to_string())
+                });
+            if let Err(e) = appended {
+                eprintln!("Failed to write audit log: {}", e);
+            }
+        }
+        self.entries.push(entry);
+    }
+
+    /// Hash of the newest entry, which vouches for the whole log. Note it down to be able to
+    /// tell later that no entry was changed or dropped since.
+    pub fn head(&self) -> [u8; 32] {
+        self.entries.last().map_or([0; 32], AuditEntry::hash)
+    }
+
+    /// Check that every entry holds the hash of the one before it. Returns the index of the
+    /// first entry that does not, which was added after an entry was changed or removed.
+    pub fn verify(&self) -> Result<(), usize> {
+        let mut previous = [0; 32];
+        for (index, entry) in self.entries.iter().enumerate() {
+            if entry.previous != previous {
+                return Err(index);
+            }
+            previous = entry.hash();
+        }
+        Ok(())
+    }
+
+    /// The entries, or only those for `key`, one per line, followed by whether the chain holds
+    pub fn lines(&self, key: Option<&str>) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|entry| key.is_none_or(|key| entry.key == key))
+            .map(|entry| entry.to_string())
+            .collect();
+        lines.push(match self.verify() {
+            Ok(()) => format!("Chain intact, {} entries, head {}", self.entries.len(), hex::encode(self.head())),
+            Err(index) => format!("Chain broken at entry {}, earlier entries were changed or removed", index + 1),
+        });
+        lines
+    }
+}
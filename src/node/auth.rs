@@ -1,22 +1,372 @@
-use ed25519_dalek::{Keypair, PublicKey, Signature, Verifier};
-use rand::rngs::OsRng;
+pub use crate::access::{
+    grant_content_hash, is_valid_group_name, revoke_content_hash, Permissions, Role, Subject,
+};
+use crate::keys::RecordKey;
+use crate::node::store::write_atomic;
+use crate::request::{self, Operation, RequestSignature, MAX_REQUEST_AGE_SECS};
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use libp2p::kad::Record;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::fs::File;
-use std::io::{Write};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 #[derive(Clone)]
 pub struct UserManager {
     users: Arc<Mutex<HashMap<String, UserCredentials>>>,
-    acl: Arc<Mutex<HashMap<String, Vec<String>>>>, // Key -> Authorized user public keys. Tell which users are allowed to acces which key
+    acl: Arc<Mutex<HashMap<String, KeyAcl>>>, // Key or prefix rule -> its owner and what each user or group may do with it. Tell which users are allowed to acces which key
+    // Group name -> hex public key of every user ever added -> whether they are still a member
+    groups: Arc<Mutex<HashMap<String, HashMap<String, GroupEntry>>>>,
+    // File the users and ACL are saved to after every change, nothing is saved when None
+    path: Option<PathBuf>,
+    // Nonces of the requests accepted recently -> their timestamp, to refuse replays
+    seen_nonces: Arc<Mutex<HashMap<[u8; 16], u64>>>,
+    // File the recent nonces are saved to, so a restarted node still refuses replays of requests
+    // it accepted before. Nothing is saved when None.
+    nonces_path: Option<PathBuf>,
+    // Public keys trusted as admins without an approval, given on the command line
+    trusted_admins: Arc<Vec<Vec<u8>>>,
 }
 
-#[allow(dead_code)]
-#[derive(Clone)]
+// Contents of the users file
+#[derive(Serialize, Deserialize)]
+struct StoredUsers {
+    users: HashMap<String, UserCredentials>,
+    acl: HashMap<String, KeyAcl>,
+    groups: HashMap<String, HashMap<String, GroupEntry>>,
+}
+
+/// A signed request kept with whatever it authorized (a role, a grant), so that
+/// every node can check for itself that the signer was allowed to authorize it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Approval {
+    pub signer: Vec<u8>,
+    // Encoded RequestSignature of the signer's request
+    pub signature: Vec<u8>,
+}
+
+impl Approval {
+    fn timestamp(&self) -> u64 {
+        RequestSignature::decode(&self.signature)
+            .map(|request| request.timestamp)
+            .unwrap_or(0)
+    }
+}
+
+
+
+/// Whether `key` is a prefix rule such as `projects/alpha/*`, applying to every key
+/// starting with `projects/alpha/`. Prefix rules only hold permissions, never data.
+pub fn is_prefix_rule(key: &str) -> bool {
+    key.ends_with('*')
+}
+
+// Whether the ACL stored under `rule` (a key or a prefix rule) applies to `key`
+fn rule_applies(rule: &str, key: &str) -> bool {
+    match rule.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => rule == key,
+    }
+}
+
+
+/// Ownership of a key, claimed by the first user to write it. The proof is the
+/// signed request of that first write.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Ownership {
+    pub key: String,
+    pub owner: Vec<u8>,
+    // Operation name of the signed write, put or put-file
+    pub op: String,
+    // Hash of the written content, covered by the signature
+    pub content_hash: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+impl Ownership {
+    // Returns when the write was signed, None if the claim does not verify
+    fn verify(&self) -> Option<u64> {
+        let op = Operation::parse(&self.op).filter(|op| op.is_write())?;
+        verify_request(&self.owner, &self.signature, op, &self.key, Some(&self.content_hash))
+            .ok()
+            .map(|request| request.timestamp)
+    }
+}
+
+/// Why the writer of a value or manifest may write its key, for nodes that do not know the
+/// key's owner and permissions: the key's ownership claim, and unless the writer owns the key
+/// or is an admin, the grant of write access the owner or an admin signed for them
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WriteProof {
+    pub ownership: Ownership,
+    pub grant: Option<Grant>,
+}
+
+/// A value or file manifest as stored in the DHT, with the signed request of the user who
+/// wrote it, so that every node storing or retrieving it can check the write
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SignedWrite {
+    // The value, or the encoded file manifest
+    pub value: Vec<u8>,
+    pub writer: Vec<u8>,
+    // Hash of `value`, covered by the signature: the value for put, the encoded manifest
+    // for put-file (which lists the hashes of the chunks in turn)
+    pub content_hash: [u8; 32],
+    pub signature: Vec<u8>,
+    // The writer's own signed user record, so that a node that does not know the writer
+    // can still check their role
+    pub user: UserCredentials,
+    // None for the first write to a key, whose writer becomes its owner
+    pub proof: Option<WriteProof>,
+}
+
+impl SignedWrite {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Failed to serialize signed write")
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+
+    /// Whether the writer signed this write to the value or manifest `key` refers to.
+    /// Says nothing about whether they were allowed to, see `UserManager::check_write`.
+    pub fn verify(&self, key: &RecordKey) -> bool {
+        let (op, key) = match key {
+            RecordKey::Value(key) => (Operation::Put, key),
+            RecordKey::Manifest(key) => (Operation::PutFile, key),
+            _ => return false,
+        };
+        // The signature only vouches for the value or manifest stored with it if it hashes to the signed hash
+        if request::content_hash(&self.value) != self.content_hash {
+            return false;
+        }
+        verify_request(&self.writer, &self.signature, op, key, Some(&self.content_hash)).is_ok()
+    }
+
+    /// Sequence number of the write, covered by the writer's signature. The writer's client
+    /// signs each write with one more than the sequence number of the write stored before it,
+    /// so nodes keep the write with the highest sequence, like IPNS does.
+    pub fn seq(&self) -> u64 {
+        RequestSignature::decode(&self.signature).map_or(0, |request| request.seq)
+    }
+
+    /// Owner of the key the write was made for: the one its proof names, or the writer of
+    /// a first write
+    pub fn owner(&self) -> &[u8] {
+        self.proof.as_ref().map_or(&self.writer, |proof| &proof.ownership.owner)
+    }
+}
+
+/// Whether `record` may take the place of `stored`, the record already kept under its key.
+/// A value or manifest is only replaced by a write with a higher sequence number, so an
+/// older write sent again by a peer cannot undo a newer one. The same write sent again is kept.
+/// A write for another owner than the stored one is refused: another user's write only
+/// replaces it with a proof that the stored write's owner, or an admin, gave them access.
+pub fn supersedes(record: &Record, stored: Option<&Record>) -> bool {
+    if !matches!(RecordKey::parse(&record.key), Some(RecordKey::Value(_) | RecordKey::Manifest(_))) {
+        return true;
+    }
+    match stored {
+        Some(stored) if stored.value == record.value => true,
+        Some(stored) => match SignedWrite::decode(&stored.value) {
+            Some(stored) => SignedWrite::decode(&record.value)
+                .is_some_and(|write| write.owner() == stored.owner() && write.seq() > stored.seq()),
+            None => true,
+        },
+        None => true,
+    }
+}
+
+// Access rules of one key or prefix rule
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct KeyAcl {
+    // Always None for prefix rules
+    owner: Option<Ownership>,
+    // Subject (hex public key or group:<name>) -> what that user or group was granted
+    entries: HashMap<String, AclEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct AclEntry {
+    permissions: Permissions,
+    // When the grant was signed, a newer grant replaces an older one
+    granted_at: u64,
+    // The grant or revocation as signed, for writes to carry as proof (see WriteProof)
+    grant: Grant,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct GroupEntry {
+    member: bool,
+    // When the admin's request was signed, a newer change replaces an older one
+    changed_at: u64,
+}
+
+/// A registered user. This is also the record published in the DHT so that
+/// every node knows the user.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct UserCredentials {
     username: String,
     public_key: Vec<u8>,
-    is_admin: bool,
+    role: Role,
+    // The user's `register` request for the username and public key, signed with the user's key.
+    // Of two users registering the same username, the one a node knew first keeps it (see learn_user).
+    signature: Vec<u8>,
+    // The admin's `role` request that gave the user its role. None for writers who were
+    // never appointed anything, and for the first admin of the network.
+    role_approval: Option<Approval>,
+    // Set once the user replaced this key pair, the key then no longer authenticates anything
+    replaced_by: Option<KeyRotation>,
+    // The key this one replaced, for a user who rotated their key
+    rotated_from: Option<Vec<u8>>,
+}
+
+impl UserCredentials {
+    fn verify(&self) -> bool {
+        let content_hash = request::content_hash(&self.public_key);
+        verify_request(&self.public_key, &self.signature, Operation::Register, &self.username, Some(&content_hash)).is_ok()
+            && self.replaced_by.as_ref().is_none_or(|rotation| {
+                rotation.old_key == self.public_key && rotation.verify(&self.username)
+            })
+    }
+
+    // When the user registered this key
+    fn registered_at(&self) -> u64 {
+        RequestSignature::decode(&self.signature)
+            .map(|request| request.timestamp)
+            .unwrap_or(u64::MAX)
+    }
+
+    // When the role was given, used to keep the latest of two versions of a user
+    fn role_timestamp(&self) -> u64 {
+        self.role_approval.as_ref().map(Approval::timestamp).unwrap_or(0)
+    }
+}
+
+/// Replacement of a user's key pair, published in the DHT as part of the user record of the
+/// old key. The old key signs a `rotate-key` request for the new one, and the new key signs
+/// a `register` request for the same username.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyRotation {
+    pub old_key: Vec<u8>,
+    pub new_key: Vec<u8>,
+    // Encoded RequestSignature of the old key's `rotate-key` request
+    pub signature: Vec<u8>,
+    // Encoded RequestSignature of the new key's `register` request
+    pub new_signature: Vec<u8>,
+}
+
+impl KeyRotation {
+    fn verify(&self, username: &str) -> bool {
+        let content_hash = request::content_hash(&self.new_key);
+        verify_request(&self.old_key, &self.signature, Operation::RotateKey, &hex::encode(&self.old_key), Some(&content_hash)).is_ok()
+            && verify_request(
+                &self.new_key,
+                &self.new_signature,
+                Operation::Register,
+                username,
+                Some(&request::content_hash(&self.new_key)),
+            )
+            .is_ok()
+    }
+}
+
+/// Permissions of a user or group on a key or prefix rule, as published in the DHT,
+/// together with the request that granted them (from an admin or a user with the grant
+/// permission). A grant without permissions is a revocation, approved by a `revoke`
+/// request from the key's owner or an admin.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Grant {
+    pub key: String,
+    pub subject: Subject,
+    pub permissions: Permissions,
+    pub approval: Approval,
+}
+
+/// A user added to or removed from a group, as published in the DHT, together with
+/// the admin's `group-add` or `group-remove` request
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Membership {
+    pub group: String,
+    pub public_key: Vec<u8>,
+    pub member: bool,
+    pub approval: Approval,
+}
+
+// ACLs that apply to `key`: its own and those of the prefix rules matching it
+fn applicable_acls<'a>(acl: &'a HashMap<String, KeyAcl>, key: &'a str) -> impl Iterator<Item = &'a KeyAcl> + 'a {
+    acl.iter().filter_map(move |(hex_rule, key_acl)| {
+        let rule = String::from_utf8(hex::decode(hex_rule).ok()?).ok()?;
+        rule_applies(&rule, key).then_some(key_acl)
+    })
+}
+
+// Everything an ACL grants to any of `subjects`
+fn granted_to(key_acl: &KeyAcl, subjects: &[String]) -> Permissions {
+    subjects
+        .iter()
+        .filter_map(|subject| key_acl.entries.get(subject))
+        .fold(Permissions::default(), |granted, entry| granted.union(entry.permissions))
+}
+
+// When a user first registered their username and with which key, following rotations back
+// to the first key, to tell two users registering the same username apart
+fn first_registration(users: &HashMap<String, UserCredentials>, user: &UserCredentials) -> (u64, Vec<u8>) {
+    let mut first = user;
+    for _ in 0..users.len() {
+        match first.rotated_from.as_ref().and_then(|old_key| users.get(&hex::encode(old_key))) {
+            Some(previous) => first = previous,
+            None => break,
+        }
+    }
+    (first.registered_at(), first.public_key.clone())
+}
+
+// Key that replaced `public_key` after any number of rotations, `public_key` itself if it was never rotated
+fn current_key(users: &HashMap<String, UserCredentials>, public_key: &[u8]) -> Vec<u8> {
+    let mut current = public_key.to_vec();
+    // Bounded, a key can only be rotated to one that was not registered yet but records
+    // from other nodes may arrive in any order
+    for _ in 0..=users.len() {
+        match users.get(&hex::encode(&current)).and_then(|user| user.replaced_by.as_ref()) {
+            Some(rotation) => current = rotation.new_key.clone(),
+            None => break,
+        }
+    }
+    current
+}
+
+fn verify_signature(public_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
+    let public_key = match PublicKey::from_bytes(public_key) {
+        Ok(pk) => pk,
+        Err(_) => return false,
+    };
+
+    let signature = match Signature::from_bytes(signature) {
+        Ok(sig) => sig,
+        Err(_) => return false,
+    };
+
+    public_key.verify(message, &signature).is_ok()
+}
+
+// Check that `signature` is a request signature of `public_key` for exactly this request,
+// however old it is. Returns the decoded signature.
+fn verify_request(
+    public_key: &[u8],
+    signature: &[u8],
+    op: Operation,
+    key: &str,
+    content_hash: Option<&[u8; 32]>,
+) -> Result<RequestSignature, String> {
+    let request = RequestSignature::decode(signature).ok_or("Malformed request signature")?;
+    let message = request::signed_message(op, key, content_hash, request.seq, request.timestamp, &request.nonce);
+    if !verify_signature(public_key, &request.signature, &message) {
+        return Err("Invalid signature".to_string());
+    }
+    Ok(request)
 }
 
 impl UserManager {
@@ -24,93 +374,884 @@ impl UserManager {
         UserManager {
             users: Arc::new(Mutex::new(HashMap::new())),
             acl: Arc::new(Mutex::new(HashMap::new())),
+            groups: Arc::new(Mutex::new(HashMap::new())),
+            path: None,
+            seen_nonces: Arc::new(Mutex::new(HashMap::new())),
+            nonces_path: None,
+            trusted_admins: Arc::new(Vec::new()),
         }
     }
 
-    pub fn register_user(&self, username: &str, is_admin: bool) -> Result<Vec<u8>, String> {
-        let mut csprng = OsRng;
-        let keypair = Keypair::generate(&mut csprng);
-        
-        let public_key = keypair.public.to_bytes().to_vec();
-        let hex_public_key = hex::encode(&public_key);
+    /// Load the users and ACL saved in `path` by a previous run, or start empty if there
+    /// is no such file yet. Every later change is written back to `path`. The nonces of
+    /// recent requests are kept next to it, in `nonces.bin`.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+
+        let stored = match fs::read(path) {
+            Ok(bytes) => bincode::deserialize(&bytes)
+                .map_err(|e| format!("Corrupted users file '{}': {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StoredUsers {
+                users: HashMap::new(),
+                acl: HashMap::new(),
+                groups: HashMap::new(),
+            },
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let nonces_path = path.with_file_name("nonces.bin");
+        let seen_nonces: HashMap<[u8; 16], u64> = match fs::read(&nonces_path) {
+            Ok(bytes) => bincode::deserialize(&bytes)
+                .map_err(|e| format!("Corrupted nonces file '{}': {}", nonces_path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        Ok(UserManager {
+            users: Arc::new(Mutex::new(stored.users)),
+            acl: Arc::new(Mutex::new(stored.acl)),
+            groups: Arc::new(Mutex::new(stored.groups)),
+            path: Some(path.to_path_buf()),
+            seen_nonces: Arc::new(Mutex::new(seen_nonces)),
+            nonces_path: Some(nonces_path),
+            trusted_admins: Arc::new(Vec::new()),
+        })
+    }
+
+    // Write the whole user table, ACL and groups to disk. Callers hold all three locks
+    // (users, then acl, then groups) so that what is saved matches what is in memory.
+    fn save(
+        &self,
+        users: &HashMap<String, UserCredentials>,
+        acl: &HashMap<String, KeyAcl>,
+        groups: &HashMap<String, HashMap<String, GroupEntry>>,
+    ) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let stored = StoredUsers {
+            users: users.clone(),
+            acl: acl.clone(),
+            groups: groups.clone(),
+        };
+        let bytes = bincode::serialize(&stored).map_err(|e| e.to_string())?;
+        write_atomic(path, &bytes).map_err(|e| format!("Failed to save users: {}", e))
+    }
+
+    /// Trust these public keys as admins without an approval. The network's first admins
+    /// cannot be appointed by anyone, so every node has to be told who they are.
+    pub fn set_trusted_admins(&mut self, public_keys: Vec<Vec<u8>>) {
+        self.trusted_admins = Arc::new(public_keys);
+    }
+
+    /// Register a new writer, or with `is_admin` an admin whose key the node trusts (see
+    /// `set_trusted_admins`). Other admins are appointed by an admin with `set_role`. The key
+    /// pair is made by the user's client, which proves holding the private key by signing a
+    /// `register` request for the username. Usernames are unique, a user's later keys come
+    /// from `rotate_key`.
+    pub fn register_user(&self, username: &str, public_key: &[u8], signature: &[u8], is_admin: bool) -> Result<(), String> {
+        if is_admin && !self.trusted_admins.iter().any(|trusted| trusted == public_key) {
+            return Err("Only keys the node was started with --admin-key for register as admins, others are appointed by an admin with the role command".to_string());
+        }
+        let content_hash = request::content_hash(public_key);
+        self.authenticate_request(public_key, signature, Operation::Register, username, Some(&content_hash))
+            .map_err(|e| format!("Invalid registration: {}", e))?;
+
+        let hex_public_key = hex::encode(public_key);
 
         let mut users = self.users.lock().map_err(|_| "Lock poisoned")?;
         
         if users.contains_key(&hex_public_key) {
             return Err("User already exists".to_string());
         }
-
-        // generate a secure location to store the private key
-        let private_key_path = format!("./private_keys/{}.private_key", username);
-        
-        let private_key = keypair.secret.to_bytes().to_vec();
-        self.store_private_key(&private_key_path, &private_key)?;
+        if users.values().any(|user| user.username == username) {
+            return Err(format!("The username '{}' is already taken", username));
+        }
 
         users.insert(
             hex_public_key.clone(),
             UserCredentials {
                 username: username.to_string(),
-                public_key: public_key.clone(),
-                is_admin,
+                public_key: public_key.to_vec(),
+                role: if is_admin { Role::Admin } else { Role::Writer },
+                signature: signature.to_vec(),
+                role_approval: None,
+                replaced_by: None,
+                rotated_from: None,
             }
         );
 
-        Ok(public_key)
+        let acl = self.acl.lock().map_err(|_| "Lock poisoned")?;
+        let groups = self.groups.lock().map_err(|_| "Lock poisoned")?;
+        if let Err(e) = self.save(&users, &acl, &groups) {
+            users.remove(&hex_public_key);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Check a signed request: the signature must cover this operation, key and content,
+    /// be recent, and its nonce must not have been used before. Returns the decoded signature.
+    pub fn authenticate_request(
+        &self,
+        public_key: &[u8],
+        signature: &[u8],
+        op: Operation,
+        key: &str,
+        content_hash: Option<&[u8; 32]>,
+    ) -> Result<RequestSignature, String> {
+        let request = verify_request(public_key, signature, op, key, content_hash)?;
+        if self.user(public_key).is_some_and(|user| user.replaced_by.is_some()) {
+            return Err("This key was replaced by a newer one".to_string());
+        }
+
+        let now = request::unix_now();
+        if request.timestamp.abs_diff(now) > MAX_REQUEST_AGE_SECS {
+            return Err("Request signature has expired".to_string());
+        }
+
+        // Only remember nonces as long as their timestamp is accepted, older ones fail the check above
+        let mut seen_nonces = self.seen_nonces.lock().map_err(|_| "Lock poisoned")?;
+        seen_nonces.retain(|_, timestamp| timestamp.abs_diff(now) <= MAX_REQUEST_AGE_SECS);
+        if seen_nonces.insert(request.nonce, request.timestamp).is_some() {
+            return Err("Request signature was already used".to_string());
+        }
+        // A request is only accepted once its nonce is saved, or it could be replayed after a restart
+        if let Some(path) = &self.nonces_path {
+            let bytes = bincode::serialize(&*seen_nonces).map_err(|e| e.to_string())?;
+            if let Err(e) = write_atomic(path, &bytes) {
+                seen_nonces.remove(&request.nonce);
+                return Err(format!("Failed to save request nonce: {}", e));
+            }
+        }
+
+        Ok(request)
     }
 
-    // securely store private key (e.g., in a file)
-    fn store_private_key(&self, path: &str, private_key: &[u8]) -> Result<(), String> {
-        let mut file = File::create(path).map_err(|e| e.to_string())?;
-        file.write_all(&private_key).map_err(|e| e.to_string())?;
+    /// Check a signed request that only an admin may make. Returns the decoded signature.
+    pub fn authorize_admin(
+        &self,
+        public_key: &[u8],
+        signature: &[u8],
+        op: Operation,
+        key: &str,
+        content_hash: Option<&[u8; 32]>,
+    ) -> Result<RequestSignature, String> {
+        let request = self.authenticate_request(public_key, signature, op, key, content_hash)?;
+        if !self.is_admin(public_key) {
+            return Err("Only admins can do this".to_string());
+        }
+        Ok(request)
+    }
 
+    /// Give a registered user a new role, as approved by an admin's `role` request
+    pub fn set_role(&self, public_key: &[u8], role: Role, approval: Approval) -> Result<(), String> {
+        let hex_public_key = hex::encode(public_key);
+
+        let mut users = self.users.lock().map_err(|_| "Lock poisoned")?;
+        let user = users.get_mut(&hex_public_key).ok_or("Unknown user")?;
+        let previous = (user.role, user.role_approval.take());
+        user.role = role;
+        user.role_approval = Some(approval);
+
+        let acl = self.acl.lock().map_err(|_| "Lock poisoned")?;
+        let groups = self.groups.lock().map_err(|_| "Lock poisoned")?;
+        if let Err(e) = self.save(&users, &acl, &groups) {
+            if let Some(user) = users.get_mut(&hex_public_key) {
+                (user.role, user.role_approval) = previous;
+            }
+            return Err(e);
+        }
         Ok(())
     }
 
-    pub fn authenticate(&self, public_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
-        let public_key = match PublicKey::from_bytes(public_key) {
-            Ok(pk) => pk,
-            Err(_) => return false,
-        };
+    /// Replace a user's key pair as signed in `rotation`. The new key takes over the user's role,
+    /// ownerships, permissions and groups, and the old key no longer authenticates anything.
+    /// Returns false, changing nothing, if the rotation is already known.
+    pub fn rotate_key(&self, rotation: KeyRotation) -> Result<bool, String> {
+        let old_hex = hex::encode(&rotation.old_key);
+        let new_hex = hex::encode(&rotation.new_key);
+
+        let mut users = self.users.lock().map_err(|_| "Lock poisoned")?;
+        let mut acl = self.acl.lock().map_err(|_| "Lock poisoned")?;
+        let mut groups = self.groups.lock().map_err(|_| "Lock poisoned")?;
 
-        let signature = match Signature::from_bytes(signature) {
-            Ok(sig) => sig,
-            Err(_) => return false,
+        let old_user = users.get(&old_hex).ok_or("Unknown user")?;
+        match &old_user.replaced_by {
+            Some(known) if known.new_key == rotation.new_key => return Ok(false),
+            Some(_) => return Err("This key was already replaced".to_string()),
+            None => {}
+        }
+        if !rotation.verify(&old_user.username) {
+            return Err("Invalid key rotation".to_string());
+        }
+        // The new key may already be known from its own record, as the user after this rotation
+        if users
+            .get(&new_hex)
+            .is_some_and(|known| known.rotated_from.as_ref() != Some(&rotation.old_key))
+        {
+            return Err("The new key is already registered".to_string());
+        }
+        let new_user = UserCredentials {
+            username: old_user.username.clone(),
+            public_key: rotation.new_key.clone(),
+            role: old_user.role,
+            signature: rotation.new_signature.clone(),
+            role_approval: old_user.role_approval.clone(),
+            replaced_by: None,
+            rotated_from: Some(rotation.old_key.clone()),
         };
 
-        public_key.verify(message, &signature).is_ok()
+        let previous = (users.clone(), acl.clone(), groups.clone());
+        users.insert(new_hex.clone(), new_user);
+        if let Some(old_user) = users.get_mut(&old_hex) {
+            old_user.replaced_by = Some(rotation);
+        }
+        // Permissions and group memberships of the old key move to the new one,
+        // ownerships are followed through the rotation (see current_key)
+        for key_acl in acl.values_mut() {
+            if let Some(entry) = key_acl.entries.remove(&old_hex) {
+                if key_acl.entries.get(&new_hex).is_none_or(|known| known.granted_at <= entry.granted_at) {
+                    key_acl.entries.insert(new_hex.clone(), entry);
+                }
+            }
+        }
+        for members in groups.values_mut() {
+            if let Some(entry) = members.remove(&old_hex) {
+                if members.get(&new_hex).is_none_or(|known| known.changed_at <= entry.changed_at) {
+                    members.insert(new_hex.clone(), entry);
+                }
+            }
+        }
+
+        if let Err(e) = self.save(&users, &acl, &groups) {
+            (*users, *acl, *groups) = previous;
+            return Err(e);
+        }
+        Ok(true)
     }
 
-    pub fn add_key_permission(&self, key: &str, authorized_public_key: &[u8]) -> Result<(), String> {
-        let hex_key = hex::encode(key);
-        let hex_public_key = hex::encode(authorized_public_key);
+    /// The key a user holds now, following `public_key` through any rotations
+    pub fn resolve_key(&self, public_key: &[u8]) -> Vec<u8> {
+        let users = self.users.lock().unwrap();
+
+        current_key(&users, public_key)
+    }
 
+    // A subject with its public key followed through rotations
+    fn resolve_subject(&self, subject: &Subject) -> Subject {
+        match subject {
+            Subject::User(public_key) => Subject::User(self.resolve_key(public_key)),
+            Subject::Group(_) => subject.clone(),
+        }
+    }
+
+    // Change the ACL and save it, leaving it as it was if saving fails
+    fn update_acl<R>(&self, update: impl FnOnce(&mut HashMap<String, KeyAcl>) -> R) -> Result<R, String> {
+        let users = self.users.lock().map_err(|_| "Lock poisoned")?;
         let mut acl = self.acl.lock().map_err(|_| "Lock poisoned")?;
-        
-        acl.entry(hex_key)
-            .or_insert_with(Vec::new)
-            .push(hex_public_key);
+        let groups = self.groups.lock().map_err(|_| "Lock poisoned")?;
 
-        Ok(())
+        let previous = acl.clone();
+        let result = update(&mut acl);
+        if let Err(e) = self.save(&users, &acl, &groups) {
+            *acl = previous;
+            return Err(e);
+        }
+        Ok(result)
+    }
+
+    /// Set what a user or group may do with a key or prefix rule as signed in `grant`, replacing
+    /// what it was granted before. Returns false, changing nothing, if a grant signed later is
+    /// already known.
+    pub fn add_key_permission(&self, grant: Grant) -> Result<bool, String> {
+        let hex_key = hex::encode(&grant.key);
+        // A grant to a key that was rotated since applies to the key that replaced it
+        let subject = self.resolve_subject(&grant.subject).to_string();
+        let granted_at = grant.approval.timestamp();
+
+        self.update_acl(|acl| {
+            let entries = &mut acl.entry(hex_key).or_default().entries;
+            if entries.get(&subject).is_some_and(|entry| entry.granted_at > granted_at) {
+                return false;
+            }
+            entries.insert(subject, AclEntry { permissions: grant.permissions, granted_at, grant });
+            true
+        })
+    }
+
+    /// Withdraw every permission of a user or group on a key, as signed in `revocation`, a grant
+    /// without permissions. Returns whether it had any permission left to withdraw, and fails,
+    /// changing nothing, if a grant signed after the revocation is already known.
+    pub fn revoke_key_permission(&self, revocation: Grant) -> Result<bool, String> {
+        let subject = self.resolve_subject(&revocation.subject);
+        let had_permissions = self
+            .key_permissions(&revocation.key)
+            .iter()
+            .any(|(granted, _)| *granted == subject);
+        // Kept as an entry without permissions, so an older grant arriving later does not undo it
+        if !self.add_key_permission(revocation)? {
+            return Err(format!("{} was granted permissions after this revocation was signed, nothing was revoked", subject));
+        }
+        Ok(had_permissions)
+    }
+
+    /// Whether the user may revoke and list permissions on `key`: an admin or the key's owner.
+    /// Prefix rules have no owner and are managed by admins only.
+    pub fn can_manage(&self, key: &str, public_key: &[u8]) -> bool {
+        self.is_admin(public_key) || self.owner(key).is_some_and(|owner| owner == public_key)
+    }
+
+    /// Users and groups granted permissions on a key or prefix rule, with what they may do.
+    /// The owner is not listed, nor are the prefix rules that also apply to a key.
+    pub fn key_permissions(&self, key: &str) -> Vec<(Subject, Permissions)> {
+        let acl = self.acl.lock().unwrap();
+
+        let mut permissions: Vec<(Subject, Permissions)> = acl
+            .get(&hex::encode(key))
+            .map(|key_acl| {
+                key_acl
+                    .entries
+                    .iter()
+                    .filter(|(_, entry)| !entry.permissions.is_empty())
+                    .filter_map(|(subject, entry)| Some((Subject::parse(subject)?, entry.permissions)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        permissions.sort_by_key(|(subject, _)| subject.to_string());
+        permissions
+    }
+
+    /// Keys and prefix rules a user owns or was granted permissions on, directly or
+    /// through a group, with what they may do. Owned keys are listed with every permission.
+    pub fn user_permissions(&self, public_key: &[u8]) -> Vec<(String, Permissions)> {
+        let subjects = self.subjects_of(public_key);
+
+        let users = self.users.lock().unwrap();
+        let acl = self.acl.lock().unwrap();
+
+        let mut permissions: Vec<(String, Permissions)> = acl
+            .iter()
+            .filter_map(|(hex_key, key_acl)| {
+                let key = String::from_utf8(hex::decode(hex_key).ok()?).ok()?;
+                if key_acl.owner.as_ref().is_some_and(|ownership| current_key(&users, &ownership.owner) == public_key) {
+                    return Some((key, Permissions::ALL));
+                }
+                let granted = granted_to(key_acl, &subjects);
+                (!granted.is_empty()).then_some((key, granted))
+            })
+            .collect();
+        permissions.sort_by(|a, b| a.0.cmp(&b.0));
+        permissions
+    }
+
+    /// Whether the user owns the key or holds all of `permissions` on it, granted on the key
+    /// itself or on a prefix rule matching it, directly or through a group
+    pub fn check_key_permission(&self, key: &str, public_key: &[u8], permissions: Permissions) -> bool {
+        let subjects = self.subjects_of(public_key);
+
+        let users = self.users.lock().unwrap();
+        let acl = self.acl.lock().unwrap();
+
+        let owns = applicable_acls(&acl, key).any(|key_acl| {
+            key_acl.owner.as_ref().is_some_and(|ownership| current_key(&users, &ownership.owner) == public_key)
+        });
+        let granted = applicable_acls(&acl, key)
+            .fold(Permissions::default(), |granted, key_acl| granted.union(granted_to(key_acl, &subjects)));
+        owns || granted.contains(permissions)
+    }
+
+    /// Whether the user may perform `op` on `key`. Admins may do anything; writers and readers
+    /// what the key's owner and grants allow, readers only reads. A key nobody owns or was
+    /// granted on yet may be written by any writer, who then becomes its owner. Prefix rules
+    /// can never be written.
+    pub fn check_access(&self, key: &str, public_key: &[u8], op: Operation) -> bool {
+        if op.needs_write_access() && is_prefix_rule(key) {
+            return false;
+        }
+        let required = Permissions::required_for(op);
+        match self.role(public_key) {
+            Some(Role::Admin) => true,
+            Some(Role::Writer) if op.needs_write_access() && self.is_unclaimed(key) => true,
+            Some(Role::Writer) => self.check_key_permission(key, public_key, required),
+            Some(Role::Reader) => required == Permissions::READ && self.check_key_permission(key, public_key, required),
+            None => false,
+        }
+    }
+
+    // Whether the key has no owner, and nobody has permissions on it or on a prefix rule matching it
+    fn is_unclaimed(&self, key: &str) -> bool {
+        let acl = self.acl.lock().unwrap();
+
+        let unclaimed = applicable_acls(&acl, key).all(|key_acl| {
+            key_acl.owner.is_none() && key_acl.entries.values().all(|entry| entry.permissions.is_empty())
+        });
+        unclaimed
+    }
+
+    /// Owner of a key, None while nobody has written it. An owner who rotated their key
+    /// is given with the key they hold now.
+    pub fn owner(&self, key: &str) -> Option<Vec<u8>> {
+        let users = self.users.lock().unwrap();
+        let acl = self.acl.lock().unwrap();
+
+        acl.get(&hex::encode(key))
+            .and_then(|key_acl| key_acl.owner.as_ref())
+            .map(|ownership| current_key(&users, &ownership.owner))
+    }
+
+    /// Record who owns a key. The time a write was signed at is chosen by the writer, so it
+    /// cannot settle who wrote first: the first claim this node learns of stands, and a claim
+    /// by another user is refused whatever time it gives. So is a claim by a user this node
+    /// does not know, signed before the user registered, or signed in the future. Returns
+    /// false, changing nothing, if the claim is refused.
+    pub fn claim_ownership(&self, ownership: Ownership) -> Result<bool, String> {
+        let claimed_at = ownership.verify().ok_or("Invalid ownership claim")?;
+        let registered_at = match self.user(&ownership.owner) {
+            Some(user) => user.registered_at(),
+            None => return Ok(false),
+        };
+        if claimed_at < registered_at || claimed_at > request::unix_now() + MAX_REQUEST_AGE_SECS {
+            return Ok(false);
+        }
+        let hex_key = hex::encode(&ownership.key);
+
+        self.update_acl(|acl| {
+            let key_acl = acl.entry(hex_key).or_default();
+            match &key_acl.owner {
+                Some(known) => known.owner == ownership.owner,
+                None => {
+                    key_acl.owner = Some(ownership);
+                    true
+                }
+            }
+        })
+    }
+
+    /// Public keys allowed to read `key`: its owner and the users granted read access,
+    /// on the key or a prefix rule matching it, directly or as members of a group
+    pub fn authorized_keys(&self, key: &str) -> Vec<Vec<u8>> {
+        let subjects: Vec<Subject> = {
+            let users = self.users.lock().unwrap();
+            let acl = self.acl.lock().unwrap();
+            applicable_acls(&acl, key)
+                .flat_map(|key_acl| {
+                    let owner = key_acl
+                        .owner
+                        .as_ref()
+                        .map(|ownership| Subject::User(current_key(&users, &ownership.owner)));
+                    let readers = key_acl
+                        .entries
+                        .iter()
+                        .filter(|(_, entry)| entry.permissions.contains(Permissions::READ))
+                        .filter_map(|(subject, _)| Subject::parse(subject));
+                    owner.into_iter().chain(readers).collect::<Vec<_>>()
+                })
+                .collect()
+        };
+
+        let mut authorized_keys: Vec<Vec<u8>> = Vec::new();
+        for subject in subjects {
+            let public_keys = match subject {
+                Subject::User(public_key) => vec![public_key],
+                Subject::Group(group) => self.group_members(&group),
+            };
+            for public_key in public_keys {
+                if !authorized_keys.contains(&public_key) {
+                    authorized_keys.push(public_key);
+                }
+            }
+        }
+        authorized_keys
+    }
+
+    /// Add a user to a group or remove them from it, as of the time the admin's request was
+    /// signed. Returns false, changing nothing, if a change signed later is already known.
+    pub fn set_group_member(&self, group: &str, public_key: &[u8], member: bool, changed_at: u64) -> Result<bool, String> {
+        let users = self.users.lock().map_err(|_| "Lock poisoned")?;
+        let acl = self.acl.lock().map_err(|_| "Lock poisoned")?;
+        let mut groups = self.groups.lock().map_err(|_| "Lock poisoned")?;
+
+        let members = groups.entry(group.to_string()).or_default();
+        let hex_public_key = hex::encode(current_key(&users, public_key));
+        if members.get(&hex_public_key).is_some_and(|entry| entry.changed_at > changed_at) {
+            return Ok(false);
+        }
+        let previous = members.insert(hex_public_key.clone(), GroupEntry { member, changed_at });
+
+        if let Err(e) = self.save(&users, &acl, &groups) {
+            let members = groups.entry(group.to_string()).or_default();
+            match previous {
+                Some(previous) => members.insert(hex_public_key, previous),
+                None => members.remove(&hex_public_key),
+            };
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Public keys of the current members of a group
+    pub fn group_members(&self, group: &str) -> Vec<Vec<u8>> {
+        let groups = self.groups.lock().unwrap();
+
+        groups
+            .get(group)
+            .map(|members| {
+                members
+                    .iter()
+                    .filter(|(_, entry)| entry.member)
+                    .filter_map(|(hex_public_key, _)| hex::decode(hex_public_key).ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Names of the groups a user is a member of
+    pub fn groups_of(&self, public_key: &[u8]) -> Vec<String> {
+        let hex_public_key = hex::encode(public_key);
+
+        let groups = self.groups.lock().unwrap();
+
+        let mut names: Vec<String> = groups
+            .iter()
+            .filter(|(_, members)| members.get(&hex_public_key).is_some_and(|entry| entry.member))
+            .map(|(group, _)| group.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    // ACL entry names that apply to a user: their public key and each of their groups
+    fn subjects_of(&self, public_key: &[u8]) -> Vec<String> {
+        let mut subjects = vec![Subject::User(public_key.to_vec()).to_string()];
+        subjects.extend(
+            self.groups_of(public_key)
+                .into_iter()
+                .map(|group| Subject::Group(group).to_string()),
+        );
+        subjects
     }
 
-    pub fn check_key_permission(&self, key: &str, public_key: &[u8]) -> bool {
-        let hex_key = hex::encode(key);
+    /// Signed credentials of a user, to publish in the DHT
+    pub fn user(&self, public_key: &[u8]) -> Option<UserCredentials> {
         let hex_public_key = hex::encode(public_key);
 
+        let users = self.users.lock().unwrap();
+
+        users.get(&hex_public_key).cloned()
+    }
+
+    /// Check a user, grant, ownership or group membership record before it is stored and take it into account.
+    /// Returns false for a record that does not verify. Values and file manifests are
+    /// checked with `check_write` and chunks and shards against their hash; other kinds of records
+    /// are not looked at and always pass.
+    pub fn apply_record(&self, record: &Record) -> bool {
+        match RecordKey::parse(&record.key) {
+            Some(record_key @ (RecordKey::Value(_) | RecordKey::Manifest(_))) => self.check_write(&record_key, &record.value),
+            Some(RecordKey::Chunk(hash) | RecordKey::Shard(hash)) => request::content_hash(&record.value) == hash,
+            Some(RecordKey::User(public_key)) => match bincode::deserialize(&record.value) {
+                Ok(user) => self.apply_user(&public_key, user),
+                Err(_) => false,
+            },
+            Some(RecordKey::Grant { key, subject }) => {
+                let grant: Grant = match bincode::deserialize(&record.value) {
+                    Ok(grant) => grant,
+                    Err(_) => return false,
+                };
+                if grant.key != key || grant.subject.to_string() != subject {
+                    return false;
+                }
+                // The signer must be allowed to grant (or revoke) on the key, as far as this node knows,
+                // with the key they hold now if they rotated it since
+                let approval = &grant.approval;
+                let signer = self.resolve_key(&approval.signer);
+                let (allowed, op, content_hash) = if grant.permissions.is_empty() {
+                    (self.can_manage(&key, &signer), Operation::Revoke, revoke_content_hash(&grant.subject))
+                } else {
+                    (
+                        self.check_access(&key, &signer, Operation::Grant),
+                        Operation::Grant,
+                        grant_content_hash(&grant.subject, grant.permissions),
+                    )
+                };
+                if !allowed || verify_request(&approval.signer, &approval.signature, op, &key, Some(&content_hash)).is_err() {
+                    return false;
+                }
+                self.add_key_permission(grant).unwrap_or(false)
+            }
+            Some(RecordKey::Owner(key)) => {
+                let ownership: Ownership = match bincode::deserialize(&record.value) {
+                    Ok(ownership) => ownership,
+                    Err(_) => return false,
+                };
+                if ownership.key != key || is_prefix_rule(&key) || ownership.verify().is_none() {
+                    return false;
+                }
+                self.claim_ownership(ownership).unwrap_or(false)
+            }
+            Some(RecordKey::Member { group, public_key }) => {
+                let membership: Membership = match bincode::deserialize(&record.value) {
+                    Ok(membership) => membership,
+                    Err(_) => return false,
+                };
+                if membership.group != group || membership.public_key != public_key {
+                    return false;
+                }
+                let approval = &membership.approval;
+                let op = if membership.member { Operation::GroupAdd } else { Operation::GroupRemove };
+                let content_hash = request::content_hash(&public_key);
+                if !self.is_admin(&self.resolve_key(&approval.signer))
+                    || verify_request(&approval.signer, &approval.signature, op, &group, Some(&content_hash)).is_err()
+                {
+                    return false;
+                }
+                self.set_group_member(&group, &public_key, membership.member, approval.timestamp())
+                    .unwrap_or(false)
+            }
+            _ => true,
+        }
+    }
+
+    // Check the signed record of the user with `public_key` and take it into account
+    fn apply_user(&self, public_key: &[u8], mut user: UserCredentials) -> bool {
+        if user.public_key != public_key || !user.verify() || !self.role_is_approved(&user) {
+            return false;
+        }
+        match user.replaced_by.take() {
+            // Know the user as it was before the rotation, then move it to the new key
+            Some(rotation) => self.learn_user(user).is_ok() && self.rotate_key(rotation).is_ok(),
+            None => self.learn_user(user).unwrap_or(false),
+        }
+    }
+
+    /// Whether a value or manifest record holds a write its writer signed and was allowed
+    /// to make. A writer the node does not know yet is learned from the user record the write
+    /// carries, and the ownership and grant in its proof are checked and taken into account,
+    /// so the node need not have the key's owner and permission records. A write that
+    /// contradicts what the node knows, such as another owner or a later revocation, is refused.
+    /// A writer who rotated their key since is checked with the key they hold now.
+    pub fn check_write(&self, record_key: &RecordKey, bytes: &[u8]) -> bool {
+        let (key, op) = match record_key {
+            RecordKey::Value(key) => (key, Operation::Put),
+            RecordKey::Manifest(key) => (key, Operation::PutFile),
+            _ => return false,
+        };
+        let write = match SignedWrite::decode(bytes) {
+            Some(write) if write.verify(record_key) => write,
+            _ => return false,
+        };
+        if self.user(&write.writer).is_none() && !self.apply_user(&write.writer, write.user) {
+            return false;
+        }
+        let writer = self.resolve_key(&write.writer);
+        if let Some(proof) = write.proof {
+            if !self.apply_write_proof(key, &writer, proof) {
+                return false;
+            }
+        }
+        self.check_access(key, &writer, op)
+    }
+
+    // Check the proof a write by `writer` to `key` carries and learn the ownership and grant in it
+    fn apply_write_proof(&self, key: &str, writer: &[u8], proof: WriteProof) -> bool {
+        let ownership = proof.ownership;
+        if ownership.key != key || ownership.verify().is_none() {
+            return false;
+        }
+        let owner = self.resolve_key(&ownership.owner);
+        match self.owner(key) {
+            Some(known) if known != owner => return false,
+            Some(_) => {}
+            // Not taken into account if the node does not know the owner, the grant still is
+            None => {
+                let _ = self.claim_ownership(ownership);
+            }
+        }
+        if owner == writer {
+            return true;
+        }
+        let grant = match proof.grant {
+            Some(grant) => grant,
+            None => return self.is_admin(writer),
+        };
+
+        let granted_to_writer = match &grant.subject {
+            Subject::User(public_key) => self.resolve_key(public_key) == writer,
+            Subject::Group(group) => self.groups_of(writer).contains(group),
+        };
+        let signer = self.resolve_key(&grant.approval.signer);
+        let content_hash = grant_content_hash(&grant.subject, grant.permissions);
+        let valid = rule_applies(&grant.key, key)
+            && grant.permissions.contains(Permissions::WRITE)
+            && granted_to_writer
+            && (signer == owner || self.is_admin(&signer))
+            && verify_request(&grant.approval.signer, &grant.approval.signature, Operation::Grant, &grant.key, Some(&content_hash)).is_ok();
+        // A revocation signed later than the grant is kept, and check_access then refuses the write
+        valid && self.add_key_permission(grant).is_ok()
+    }
+
+    /// Proof that `writer` may write `key`, for a write to carry (see WriteProof). None while
+    /// the node knows no owner of the key.
+    pub fn write_proof(&self, key: &str, writer: &[u8]) -> Option<WriteProof> {
+        let subjects = self.subjects_of(writer);
+
+        let users = self.users.lock().unwrap();
         let acl = self.acl.lock().unwrap();
-        
-        acl.get(&hex_key)
-            .map(|authorized_keys| authorized_keys.contains(&hex_public_key))
-            .unwrap_or(false)
+
+        let ownership = acl.get(&hex::encode(key)).and_then(|key_acl| key_acl.owner.clone())?;
+        if current_key(&users, &ownership.owner) == writer {
+            return Some(WriteProof { ownership, grant: None });
+        }
+        // None for an admin without a grant, who needs none
+        let grant = applicable_acls(&acl, key)
+            .flat_map(|key_acl| subjects.iter().filter_map(|subject| key_acl.entries.get(subject)))
+            .find(|entry| entry.permissions.contains(Permissions::WRITE))
+            .map(|entry| entry.grant.clone());
+        Some(WriteProof { ownership, grant })
     }
 
-    // pub fn is_admin(&self, public_key: &[u8]) -> bool {
-    //     let hex_public_key = hex::encode(public_key);
-        
-    //     let users = self.users.lock().unwrap();
-        
-    //     users.get(&hex_public_key)
-    //         .map(|user| user.is_admin)
-    //         .unwrap_or(false)
-    // }
-}
\ No newline at end of file
+    // Whether the user's role was given by an admin. Writers need no approval, and an
+    // unapproved admin is only trusted if its key is one of the trusted admin keys. A user
+    // who rotated their key keeps the role of the old key.
+    fn role_is_approved(&self, user: &UserCredentials) -> bool {
+        let carried_over = user.rotated_from.as_ref().and_then(|old_key| self.user(old_key)).is_some_and(|old_user| {
+            old_user.role == user.role
+                && old_user.replaced_by.is_some_and(|rotation| rotation.new_key == user.public_key)
+        });
+        if carried_over {
+            return true;
+        }
+        match &user.role_approval {
+            None => match user.role {
+                Role::Writer => true,
+                Role::Admin => self.trusted_admins.contains(&user.public_key),
+                Role::Reader => false,
+            },
+            Some(approval) => {
+                let content_hash = request::content_hash(user.role.as_str().as_bytes());
+                self.is_admin(&self.resolve_key(&approval.signer))
+                    && verify_request(
+                        &approval.signer,
+                        &approval.signature,
+                        Operation::SetRole,
+                        &hex::encode(&user.public_key),
+                        Some(&content_hash),
+                    )
+                    .is_ok()
+            }
+        }
+    }
+
+    // Add a user registered on another node, or a newer role of a known user.
+    // Returns false for an older version of a user than the one known.
+    fn learn_user(&self, user: UserCredentials) -> Result<bool, String> {
+        let hex_public_key = hex::encode(&user.public_key);
+        let mut users = self.users.lock().map_err(|_| "Lock poisoned")?;
+        match users.get(&hex_public_key) {
+            // A replaced key stays replaced
+            Some(known) if known.replaced_by.is_some() => return Ok(false),
+            Some(known) if known.role_timestamp() > user.role_timestamp() => return Ok(false),
+            Some(known) if known.role_timestamp() == user.role_timestamp() => return Ok(true),
+            _ => {}
+        }
+
+        // Another user registered under the same username. The user the node knew first keeps
+        // it: when the registration was signed is up to its signer, so it cannot settle who was first.
+        let registration = first_registration(&users, &user);
+        let taken = users
+            .values()
+            .filter(|known| known.username == user.username)
+            .any(|known| first_registration(&users, known).1 != registration.1);
+        if taken {
+            eprintln!(
+                "Key {} registered '{}', which another user already holds, refusing it",
+                hex::encode(&user.public_key),
+                user.username
+            );
+            return Ok(false);
+        }
+
+        let previous = users.clone();
+        users.insert(hex_public_key, user);
+
+        let acl = self.acl.lock().map_err(|_| "Lock poisoned")?;
+        let groups = self.groups.lock().map_err(|_| "Lock poisoned")?;
+        if let Err(e) = self.save(&users, &acl, &groups) {
+            *users = previous;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Public keys registered under a username: the key in use first, then the keys it replaced
+    pub fn keys_of(&self, username: &str) -> Vec<Vec<u8>> {
+        let users = self.users.lock().unwrap();
+
+        let mut keys: Vec<&UserCredentials> = users.values().filter(|user| user.username == username).collect();
+        keys.sort_by_key(|user| (user.replaced_by.is_some(), std::cmp::Reverse(user.registered_at())));
+        keys.into_iter().map(|user| user.public_key.clone()).collect()
+    }
+
+    /// Every known username, in order
+    pub fn usernames(&self) -> Vec<String> {
+        let users = self.users.lock().unwrap();
+
+        let mut usernames: Vec<String> = users.values().map(|user| user.username.clone()).collect();
+        usernames.sort();
+        usernames.dedup();
+        usernames
+    }
+
+    pub fn role(&self, public_key: &[u8]) -> Option<Role> {
+        let hex_public_key = hex::encode(public_key);
+
+        let users = self.users.lock().unwrap();
+
+        // A replaced key has no role left, the key that replaced it took it over
+        users
+            .get(&hex_public_key)
+            .filter(|user| user.replaced_by.is_none())
+            .map(|user| user.role)
+    }
+
+    pub fn is_admin(&self, public_key: &[u8]) -> bool {
+        self.role(public_key) == Some(Role::Admin)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_writer(public_key: &[u8]) -> UserManager {
+        let user_manager = UserManager::new();
+        user_manager.users.lock().unwrap().insert(
+            hex::encode(public_key),
+            UserCredentials {
+                username: "writer".to_string(),
+                public_key: public_key.to_vec(),
+                role: Role::Writer,
+                signature: Vec::new(),
+                role_approval: None,
+                replaced_by: None,
+                rotated_from: None,
+            },
+        );
+        user_manager
+    }
+
+    // put-file --encrypt asks for the recipients before it uploads, so a writer who may
+    // put a file on a fresh key has to be able to ask for them too
+    #[test]
+    fn writer_can_encrypt_upload_a_fresh_key() {
+        let writer = [7u8; 32];
+        let user_manager = with_writer(&writer);
+
+        assert!(user_manager.check_access("report.pdf", &writer, Operation::Recipients));
+        assert!(user_manager.check_access("report.pdf", &writer, Operation::PutFile));
+        assert!(!user_manager.check_access("report.pdf", &[8u8; 32], Operation::Recipients));
+    }
+}
@@ -0,0 +1,119 @@
+use libp2p::identity::{ed25519, secp256k1, Keypair};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+/// Kind of key pair a node identifies itself with, its PeerId is derived from the public key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyType {
+    Ed25519,
+    Secp256k1,
+}
+
+impl KeyType {
+    pub fn parse(key_type: &str) -> Option<Self> {
+        match key_type {
+            "ed25519" => Some(KeyType::Ed25519),
+            "secp256k1" => Some(KeyType::Secp256k1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyType::Ed25519 => "ed25519",
+            KeyType::Secp256k1 => "secp256k1",
+        }
+    }
+}
+
+// On-disk form of a node's key pair
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+    key_type: String,
+    // The ed25519 key pair as 64 bytes, or the secp256k1 secret key as 32 bytes
+    secret: Vec<u8>,
+}
+
+pub fn generate(key_type: KeyType) -> Keypair {
+    match key_type {
+        KeyType::Ed25519 => Keypair::generate_ed25519(),
+        KeyType::Secp256k1 => Keypair::generate_secp256k1(),
+    }
+}
+
+/// Load the node's key pair saved in `path`, or generate one and save it there if there is
+/// no such file yet, so the node keeps its PeerId across restarts. `key_type` picks the kind
+/// of a new key pair (secp256k1 by default) and, when given, must match a saved one.
+pub fn load_or_create(path: &Path, key_type: Option<KeyType>) -> Result<Keypair, String> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            let (stored_type, keypair) = decode(&bytes)
+                .ok_or_else(|| format!("Corrupted identity file '{}'", path.display()))?;
+            match key_type {
+                Some(key_type) if key_type != stored_type => Err(format!(
+                    "Identity file '{}' holds a {} key pair, not {}",
+                    path.display(),
+                    stored_type.as_str(),
+                    key_type.as_str()
+                )),
+                _ => Ok(keypair),
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let keypair = generate(key_type.unwrap_or(KeyType::Secp256k1));
+            save(path, &keypair)?;
+            Ok(keypair)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn decode(bytes: &[u8]) -> Option<(KeyType, Keypair)> {
+    let stored: StoredIdentity = bincode::deserialize(bytes).ok()?;
+    let mut secret = stored.secret;
+    match KeyType::parse(&stored.key_type)? {
+        KeyType::Ed25519 => {
+            let keypair = ed25519::Keypair::decode(&mut secret).ok()?;
+            Some((KeyType::Ed25519, Keypair::Ed25519(keypair)))
+        }
+        KeyType::Secp256k1 => {
+            let secret_key = secp256k1::SecretKey::from_bytes(&mut secret).ok()?;
+            Some((KeyType::Secp256k1, Keypair::Secp256k1(secret_key.into())))
+        }
+    }
+}
+
+// Write the key pair to `path`, readable by the current OS user only
+fn save(path: &Path, keypair: &Keypair) -> Result<(), String> {
+    let stored = match keypair {
+        Keypair::Ed25519(keypair) => StoredIdentity {
+            key_type: KeyType::Ed25519.as_str().to_string(),
+            secret: keypair.encode().to_vec(),
+        },
+        Keypair::Secp256k1(keypair) => StoredIdentity {
+            key_type: KeyType::Secp256k1.as_str().to_string(),
+            secret: keypair.secret().to_bytes().to_vec(),
+        },
+        #[allow(unreachable_patterns)]
+        _ => return Err("Only ed25519 and secp256k1 node identities can be saved".to_string()),
+    };
+    let bytes = bincode::serialize(&stored).map_err(|e| e.to_string())?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .map_err(|e| format!("Failed to save identity to '{}': {}", path.display(), e))?;
+    file.write_all(&bytes).map_err(|e| e.to_string())?;
+    file.sync_all().map_err(|e| e.to_string())
+}
@@ -0,0 +1,499 @@
+use libp2p::{
+    Multiaddr, PeerId,
+    kad::{
+        kbucket,
+        record::{Key, ProviderRecord, Record, store::{Error, RecordStore, Result}},
+        K_VALUE,
+    },
+};
+use crate::keys::RecordKey;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{hash_map, hash_set, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A Kademlia record store that keeps every record on disk.
+///
+/// All records are kept in memory for lookups (like `MemoryStore`) and every
+/// change is written through to `<data_dir>/records` and `<data_dir>/providers`,
+/// so a restarted node comes back with the records it held before.
+/// A store opened with `in_memory` skips the disk entirely.
+pub struct DiskStore {
+    /// The identity of the peer owning the store.
+    local_key: kbucket::Key<PeerId>,
+    /// The configuration of the store.
+    config: DiskStoreConfig,
+    /// Directory holding one file per stored record, `None` when not persisting.
+    records_dir: Option<PathBuf>,
+    /// Directory holding one file per key with provider records, `None` when not persisting.
+    providers_dir: Option<PathBuf>,
+    /// The stored (regular) records.
+    records: HashMap<Key, Record>,
+    /// The stored provider records.
+    providers: HashMap<Key, Vec<ProviderRecord>>,
+    /// The set of all provider records for which the local node is the provider.
+    provided: HashSet<ProviderRecord>,
+    /// Checks every record before it is stored, see `set_validator`.
+    validator: Option<RecordValidator>,
+    /// Why the validator refused the record of the last failed `put`, see `take_refusal`.
+    refusal: Option<String>,
+}
+
+/// Called with every record about to be stored, whether it was put locally or
+/// received from a peer, and the record it would replace if there is one.
+/// Returning an error, saying why, refuses the record.
+pub type RecordValidator = Box<dyn Fn(&Record, Option<&Record>) -> std::result::Result<(), String> + Send>;
+
+/// Record values must be smaller than this many bytes in a store with the default configuration.
+pub const MAX_VALUE_BYTES: usize = 65 * 1024;
+
+/// Configuration for a `DiskStore`.
+#[derive(Debug)]
+pub struct DiskStoreConfig {
+    /// The maximum number of records.
+    pub max_records: usize,
+    /// The maximum size of record values, in bytes.
+    pub max_value_bytes: usize,
+    /// The maximum number of providers stored for a key.
+    pub max_providers_per_key: usize,
+    /// The maximum number of keys with provider records.
+    pub max_provided_keys: usize,
+}
+
+impl Default for DiskStoreConfig {
+    fn default() -> Self {
+        Self {
+            // files are split into many records, so allow far more than MemoryStore does
+            max_records: 64 * 1024,
+            max_value_bytes: MAX_VALUE_BYTES,
+            max_providers_per_key: K_VALUE.get(),
+            max_provided_keys: 1024,
+        }
+    }
+}
+
+// On-disk form of a `Record`. `Instant` has no meaning across restarts,
+// so expiry is stored as a unix timestamp.
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    publisher: Option<Vec<u8>>,
+    expires: Option<u64>,
+}
+
+// On-disk form of the provider records for one key.
+#[derive(Serialize, Deserialize)]
+struct StoredProviders {
+    key: Vec<u8>,
+    providers: Vec<StoredProvider>,
+}
+
+// On-disk form of a `ProviderRecord`.
+#[derive(Serialize, Deserialize)]
+struct StoredProvider {
+    provider: Vec<u8>,
+    addresses: Vec<Vec<u8>>,
+    expires: Option<u64>,
+}
+
+impl DiskStore {
+    /// Opens (or creates) a store in `data_dir` with a default configuration.
+    pub fn new(local_id: PeerId, data_dir: &Path) -> io::Result<Self> {
+        Self::with_config(local_id, data_dir, Default::default())
+    }
+
+    /// Opens (or creates) a store in `data_dir` with the given configuration,
+    /// loading every record that was persisted by a previous run.
+    pub fn with_config(local_id: PeerId, data_dir: &Path, config: DiskStoreConfig) -> io::Result<Self> {
+        let records_dir = data_dir.join("records");
+        let providers_dir = data_dir.join("providers");
+        fs::create_dir_all(&records_dir)?;
+        fs::create_dir_all(&providers_dir)?;
+
+        let mut store = DiskStore {
+            records_dir: Some(records_dir.clone()),
+            providers_dir: Some(providers_dir.clone()),
+            ..Self::in_memory_with_config(local_id, config)
+        };
+        store.load(&records_dir, &providers_dir)?;
+
+        Ok(store)
+    }
+
+    /// Creates a store that is never written to disk, for nodes started without `--data-dir`.
+    pub fn in_memory(local_id: PeerId) -> Self {
+        Self::in_memory_with_config(local_id, Default::default())
+    }
+
+    fn in_memory_with_config(local_id: PeerId, config: DiskStoreConfig) -> Self {
+        DiskStore {
+            local_key: kbucket::Key::from(local_id),
+            config,
+            records_dir: None,
+            providers_dir: None,
+            records: HashMap::new(),
+            providers: HashMap::new(),
+            provided: HashSet::new(),
+            validator: None,
+            refusal: None,
+        }
+    }
+
+    /// Install the check run on records before they are stored. Records already
+    /// in the store (e.g. loaded from disk) are not checked again.
+    pub fn set_validator(&mut self, validator: RecordValidator) {
+        self.validator = Some(validator);
+    }
+
+    /// Why the validator refused the record of the last `put`, if that is why it failed.
+    /// Kademlia only passes on the store's error, which has no variant for this.
+    pub fn take_refusal(&mut self) -> Option<String> {
+        self.refusal.take()
+    }
+
+    fn load(&mut self, records_dir: &Path, providers_dir: &Path) -> io::Result<()> {
+        let now = Instant::now();
+
+        for entry in fs::read_dir(records_dir)? {
+            let path = entry?.path();
+            // leftovers of an interrupted write_atomic
+            if path.extension().is_some() {
+                continue;
+            }
+            let record = match fs::read(&path)
+                .ok()
+                .and_then(|bytes| bincode::deserialize::<StoredRecord>(&bytes).ok())
+                .and_then(decode_record)
+            {
+                Some(record) => record,
+                None => {
+                    eprintln!("Skipping unreadable record file '{}'", path.display());
+                    continue;
+                }
+            };
+            if record.is_expired(now) {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            self.records.insert(record.key.clone(), record);
+        }
+
+        for entry in fs::read_dir(providers_dir)? {
+            let path = entry?.path();
+            if path.extension().is_some() {
+                continue;
+            }
+            let stored: StoredProviders = match fs::read(&path)
+                .ok()
+                .and_then(|bytes| bincode::deserialize(&bytes).ok())
+            {
+                Some(stored) => stored,
+                None => {
+                    eprintln!("Skipping unreadable provider file '{}'", path.display());
+                    continue;
+                }
+            };
+            let key = Key::from(stored.key);
+            let providers: Vec<ProviderRecord> = stored
+                .providers
+                .into_iter()
+                .filter_map(|p| decode_provider(key.clone(), p))
+                .filter(|p| !p.is_expired(now))
+                .collect();
+            for p in &providers {
+                if self.local_key.preimage() == &p.provider {
+                    self.provided.insert(p.clone());
+                }
+            }
+            if !providers.is_empty() {
+                self.providers.insert(key, providers);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record_path(&self, key: &Key) -> Option<PathBuf> {
+        self.records_dir.as_ref().map(|dir| dir.join(file_name(key)))
+    }
+
+    fn provider_path(&self, key: &Key) -> Option<PathBuf> {
+        self.providers_dir.as_ref().map(|dir| dir.join(file_name(key)))
+    }
+
+    fn persist_record(&self, record: &Record) {
+        let path = match self.record_path(&record.key) {
+            Some(path) => path,
+            None => return,
+        };
+        let bytes = match bincode::serialize(&encode_record(record)) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Failed to encode record: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = write_atomic(&path, &bytes) {
+            eprintln!("Failed to persist record: {:?}", e);
+        }
+    }
+
+    fn persist_providers(&self, key: &Key) {
+        let path = match self.provider_path(key) {
+            Some(path) => path,
+            None => return,
+        };
+        let result = match self.providers.get(key) {
+            Some(providers) => {
+                let stored = StoredProviders {
+                    key: key.to_vec(),
+                    providers: providers.iter().map(encode_provider).collect(),
+                };
+                match bincode::serialize(&stored) {
+                    Ok(bytes) => write_atomic(&path, &bytes),
+                    Err(e) => Err(io::Error::other(e)),
+                }
+            }
+            None => remove_if_exists(&path),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to persist provider records: {:?}", e);
+        }
+    }
+}
+
+impl<'a> RecordStore<'a> for DiskStore {
+    type RecordsIter = iter::Map<
+        iter::Filter<hash_map::Values<'a, Key, Record>, fn(&&'a Record) -> bool>,
+        fn(&'a Record) -> Cow<'a, Record>
+    >;
+
+    type ProvidedIter = iter::Map<
+        hash_set::Iter<'a, ProviderRecord>,
+        fn(&'a ProviderRecord) -> Cow<'a, ProviderRecord>
+    >;
+
+    fn get(&'a self, k: &Key) -> Option<Cow<'a, Record>> {
+        self.records.get(k).map(Cow::Borrowed)
+    }
+
+    fn put(&'a mut self, mut r: Record) -> Result<()> {
+        self.refusal = None;
+        if r.value.len() >= self.config.max_value_bytes {
+            return Err(Error::ValueTooLarge);
+        }
+
+        let num_records = self.records.len();
+        if !self.records.contains_key(&r.key) && num_records >= self.config.max_records {
+            return Err(Error::MaxRecords);
+        }
+
+        // The store errors have no variant for an invalid record, so the reason is logged here.
+        // Any error makes Kademlia refuse the record, so the sender sees the put fail.
+        if let Some(validator) = &self.validator {
+            if let Err(reason) = validator(&r, self.records.get(&r.key)) {
+                match RecordKey::parse(&r.key) {
+                    Some(key) => eprintln!("Refused record {}: {}", key, reason),
+                    None => eprintln!("Refused record: {}", reason),
+                }
+                self.refusal = Some(reason);
+                return Err(Error::ValueTooLarge);
+            }
+        }
+
+        // A shard is stored on a single peer, which keeps it rather than letting the
+        // TTL Kademlia gives every received record run out
+        if RecordKey::is_shard(&r.key) {
+            r.expires = None;
+        }
+
+        self.persist_record(&r);
+        self.records.insert(r.key.clone(), r);
+
+        Ok(())
+    }
+
+    fn remove(&'a mut self, k: &Key) {
+        if self.records.remove(k).is_none() {
+            return;
+        }
+        if let Some(path) = self.record_path(k) {
+            if let Err(e) = remove_if_exists(&path) {
+                eprintln!("Failed to remove record file: {:?}", e);
+            }
+        }
+    }
+
+    // Kademlia republishes and replicates the records listed here to the closest peers
+    // of their key. Shards are left out so they stay on the peer they were put on.
+    fn records(&'a self) -> Self::RecordsIter {
+        let replicated: fn(&&'a Record) -> bool = |r| !RecordKey::is_shard(&r.key);
+        self.records.values().filter(replicated).map(Cow::Borrowed)
+    }
+
+    fn add_provider(&'a mut self, record: ProviderRecord) -> Result<()> {
+        let num_keys = self.providers.len();
+        if !self.providers.contains_key(&record.key) && num_keys >= self.config.max_provided_keys {
+            return Err(Error::MaxProvidedKeys);
+        }
+
+        let key = record.key.clone();
+        let is_local = self.local_key.preimage() == &record.provider;
+        let max_providers = self.config.max_providers_per_key;
+        let providers = self.providers.entry(key.clone()).or_default();
+
+        if let Some(i) = providers.iter().position(|p| p.provider == record.provider) {
+            // In-place update of an existing provider record.
+            if is_local {
+                self.provided.replace(record.clone());
+            }
+            providers[i] = record;
+        } else {
+            // Keep the providers closest to the key, like MemoryStore does.
+            let target = kbucket::Key::new(key.clone());
+            let distance = kbucket::Key::from(record.provider).distance(&target);
+            let position = providers
+                .iter()
+                .position(|p| distance < kbucket::Key::from(p.provider).distance(&target));
+
+            match position {
+                Some(i) => {
+                    if is_local {
+                        self.provided.insert(record.clone());
+                    }
+                    providers.insert(i, record);
+                    if providers.len() > max_providers {
+                        if let Some(p) = providers.pop() {
+                            self.provided.remove(&p);
+                        }
+                    }
+                }
+                None if providers.len() < max_providers => {
+                    if is_local {
+                        self.provided.insert(record.clone());
+                    }
+                    providers.push(record);
+                }
+                None => {}
+            }
+        }
+
+        self.persist_providers(&key);
+        Ok(())
+    }
+
+    fn providers(&'a self, key: &Key) -> Vec<ProviderRecord> {
+        self.providers.get(key).cloned().unwrap_or_default()
+    }
+
+    fn provided(&'a self) -> Self::ProvidedIter {
+        self.provided.iter().map(Cow::Borrowed)
+    }
+
+    fn remove_provider(&'a mut self, key: &Key, provider: &PeerId) {
+        if let hash_map::Entry::Occupied(mut e) = self.providers.entry(key.clone()) {
+            let providers = e.get_mut();
+            if let Some(i) = providers.iter().position(|p| &p.provider == provider) {
+                let p = providers.remove(i);
+                self.provided.remove(&p);
+            }
+            if providers.is_empty() {
+                e.remove();
+            }
+            self.persist_providers(key);
+        }
+    }
+}
+
+// Keys can be far longer than a file name may be, so files are named by the hash of
+// the key. The key itself is stored inside the file.
+fn file_name(key: &Key) -> String {
+    blake3::hash(key.as_ref()).to_hex().to_string()
+}
+
+// Write to a temporary file first so a crash never leaves a half-written record behind.
+// Also used for the users file (see node/auth.rs).
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn instant_to_unix(instant: Instant) -> u64 {
+    let now = Instant::now();
+    let system_time = if instant > now {
+        SystemTime::now() + (instant - now)
+    } else {
+        SystemTime::now() - (now - instant)
+    };
+    system_time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn unix_to_instant(secs: u64) -> Instant {
+    let expires = UNIX_EPOCH + Duration::from_secs(secs);
+    let now = Instant::now();
+    match expires.duration_since(SystemTime::now()) {
+        Ok(remaining) => now + remaining,
+        // already in the past, the record is expired
+        Err(_) => now,
+    }
+}
+
+fn encode_record(record: &Record) -> StoredRecord {
+    StoredRecord {
+        key: record.key.to_vec(),
+        value: record.value.clone(),
+        publisher: record.publisher.map(|p| p.to_bytes()),
+        expires: record.expires.map(instant_to_unix),
+    }
+}
+
+fn decode_record(stored: StoredRecord) -> Option<Record> {
+    let publisher = match stored.publisher {
+        Some(bytes) => Some(PeerId::from_bytes(&bytes).ok()?),
+        None => None,
+    };
+    Some(Record {
+        key: Key::from(stored.key),
+        value: stored.value,
+        publisher,
+        expires: stored.expires.map(unix_to_instant),
+    })
+}
+
+fn encode_provider(record: &ProviderRecord) -> StoredProvider {
+    StoredProvider {
+        provider: record.provider.to_bytes(),
+        addresses: record.addresses.iter().map(|a| a.to_vec()).collect(),
+        expires: record.expires.map(instant_to_unix),
+    }
+}
+
+fn decode_provider(key: Key, stored: StoredProvider) -> Option<ProviderRecord> {
+    Some(ProviderRecord {
+        key,
+        provider: PeerId::from_bytes(&stored.provider).ok()?,
+        expires: stored.expires.map(unix_to_instant),
+        addresses: stored
+            .addresses
+            .into_iter()
+            .filter_map(|a| Multiaddr::try_from(a).ok())
+            .collect(),
+    })
+}
@@ -0,0 +1,94 @@
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// How many dials to a peer in a row may fail before it is taken out of the routing table
+pub const MAX_FAILED_DIALS: u32 = 3;
+
+/// What the node knows about whether a peer is reachable
+#[derive(Debug, Clone, Default)]
+pub struct PeerState {
+    // Connections currently open to the peer
+    pub connections: usize,
+    // When a connection to the peer was last open
+    pub last_seen: Option<Instant>,
+    // Dials that failed since the last successful connection
+    pub failed_dials: u32,
+    // Taken out of the routing table after too many failed dials
+    pub pruned: bool,
+}
+
+impl PeerState {
+    pub fn is_connected(&self) -> bool {
+        self.connections > 0
+    }
+}
+
+/// Liveness of every peer the node has been connected to, dialed or discovered, kept up to
+/// date from connection and mDNS events
+#[derive(Default)]
+pub struct PeerLiveness {
+    peers: HashMap<PeerId, PeerState>,
+}
+
+impl PeerLiveness {
+    pub fn connected(&mut self, peer: PeerId) {
+        let state = self.peers.entry(peer).or_default();
+        state.connections += 1;
+        state.last_seen = Some(Instant::now());
+        state.failed_dials = 0;
+        state.pruned = false;
+    }
+
+    pub fn disconnected(&mut self, peer: PeerId) {
+        let state = self.peers.entry(peer).or_default();
+        state.connections = state.connections.saturating_sub(1);
+        state.last_seen = Some(Instant::now());
+    }
+
+    /// Note a dial to the peer that failed on every address. Returns true once it failed
+    /// MAX_FAILED_DIALS times in a row, when the peer should be taken out of the routing table.
+    pub fn dial_failed(&mut self, peer: PeerId) -> bool {
+        let state = self.peers.entry(peer).or_default();
+        state.failed_dials += 1;
+        !state.is_connected() && !state.pruned && state.failed_dials >= MAX_FAILED_DIALS
+    }
+
+    /// A peer found (again) by mDNS or dialed by hand gets another chance
+    pub fn discovered(&mut self, peer: PeerId) {
+        let state = self.peers.entry(peer).or_default();
+        state.failed_dials = 0;
+        state.pruned = false;
+    }
+
+    pub fn pruned(&mut self, peer: PeerId) {
+        self.peers.entry(peer).or_default().pruned = true;
+    }
+
+    pub fn is_connected(&self, peer: &PeerId) -> bool {
+        self.peers.get(peer).is_some_and(PeerState::is_connected)
+    }
+
+    /// One line per known peer, connected peers first
+    pub fn status(&self) -> Vec<String> {
+        let mut peers: Vec<(&PeerId, &PeerState)> = self.peers.iter().collect();
+        peers.sort_by_key(|(peer, state)| (!state.is_connected(), peer.to_base58()));
+        peers
+            .into_iter()
+            .map(|(peer, state)| {
+                let mut line = match (state.is_connected(), state.last_seen) {
+                    (true, _) => format!("{}: connected ({} connections)", peer, state.connections),
+                    (false, Some(last_seen)) => format!("{}: last seen {}s ago", peer, last_seen.elapsed().as_secs()),
+                    (false, None) => format!("{}: never connected", peer),
+                };
+                if state.failed_dials > 0 {
+                    line.push_str(&format!(", {} failed dials", state.failed_dials));
+                }
+                if state.pruned {
+                    line.push_str(", removed from the routing table");
+                }
+                line
+            })
+            .collect()
+    }
+}
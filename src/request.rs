@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How far a request's timestamp may be from the node's clock, in seconds.
+/// Older requests are refused, so a node only has to remember nonces this long.
+/// Each node remembers the nonces it accepted itself (across restarts if it has a data
+/// directory), so within this window a request can still be replayed to another node.
+pub const MAX_REQUEST_AGE_SECS: u64 = 5 * 60;
+
+/// Operation a signature authorizes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Put,
+    Get,
+    PutFile,
+    GetFile,
+    // Remove the value or file stored under a key
+    Delete,
+    // Give a user or group permissions on a key (the signed content is the subject and the permissions)
+    Grant,
+    // Withdraw a user's or group's permissions on a key (the signed content is the subject)
+    Revoke,
+    // List the users permitted on a key
+    ListAcl,
+    // List the keys the user whose hex public key is the key is permitted on
+    ListUserAcl,
+    // Give the user whose hex public key is the key a role (the signed content is the role name)
+    SetRole,
+    // Add a user to the group named by the key (the signed content is their public key)
+    GroupAdd,
+    // Remove a user from the group named by the key (the signed content is their public key)
+    GroupRemove,
+    // Replace the key pair of the user whose hex public key is the key (the signed content is the new public key)
+    RotateKey,
+    // Register the username that is the key for a key pair (the signed content is its public key),
+    // signed with that key pair
+    Register,
+    // List the audit log entries for the key, or every entry for an empty key
+    Audit,
+    // List the public keys the file key of an encrypted file to store under the key is
+    // wrapped for, asked by the writer's client before it encrypts the file
+    Recipients,
+}
+
+impl Operation {
+    /// Parse the operation names used by the `sign` command
+    pub fn parse(op: &str) -> Option<Self> {
+        match op {
+            "put" => Some(Operation::Put),
+            "get" => Some(Operation::Get),
+            "put-file" => Some(Operation::PutFile),
+            "get-file" => Some(Operation::GetFile),
+            "delete" => Some(Operation::Delete),
+            "grant" => Some(Operation::Grant),
+            "revoke" => Some(Operation::Revoke),
+            "acl" => Some(Operation::ListAcl),
+            "acl-user" => Some(Operation::ListUserAcl),
+            "role" => Some(Operation::SetRole),
+            "group-add" => Some(Operation::GroupAdd),
+            "group-remove" => Some(Operation::GroupRemove),
+            "rotate-key" => Some(Operation::RotateKey),
+            "register" => Some(Operation::Register),
+            "audit" => Some(Operation::Audit),
+            "recipients" => Some(Operation::Recipients),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Put => "put",
+            Operation::Get => "get",
+            Operation::PutFile => "put-file",
+            Operation::GetFile => "get-file",
+            Operation::Delete => "delete",
+            Operation::Grant => "grant",
+            Operation::Revoke => "revoke",
+            Operation::ListAcl => "acl",
+            Operation::ListUserAcl => "acl-user",
+            Operation::SetRole => "role",
+            Operation::GroupAdd => "group-add",
+            Operation::GroupRemove => "group-remove",
+            Operation::RotateKey => "rotate-key",
+            Operation::Register => "register",
+            Operation::Audit => "audit",
+            Operation::Recipients => "recipients",
+        }
+    }
+
+    /// Whether the operation stores data under its key
+    pub fn is_write(&self) -> bool {
+        matches!(self, Operation::Put | Operation::PutFile)
+    }
+
+    /// Whether the operation needs permission to write the key: the writes themselves, and
+    /// asking for the recipients of a file about to be written
+    pub fn needs_write_access(&self) -> bool {
+        self.is_write() || matches!(self, Operation::Recipients)
+    }
+
+    /// Whether the signature covers a content hash as well as the key
+    pub fn signs_content(&self) -> bool {
+        !matches!(
+            self,
+            Operation::Get
+                | Operation::GetFile
+                | Operation::Delete
+                | Operation::ListAcl
+                | Operation::ListUserAcl
+                | Operation::Audit
+                | Operation::Recipients
+        )
+    }
+}
+
+/// What is passed as `<sig>` on the command line: a signature that is only valid
+/// for one operation on one key (and one content for writes), for a short time, once.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestSignature {
+    // Unix time in seconds the request was signed at
+    pub timestamp: u64,
+    // Random value a node accepts only once
+    pub nonce: [u8; 16],
+    // For put and put-file, one more than the sequence number of the write stored under the
+    // key, so that nodes keep the latest write. 0 for other requests.
+    pub seq: u64,
+    // ed25519 signature over `signed_message`
+    pub signature: Vec<u8>,
+}
+
+impl RequestSignature {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Failed to serialize request signature")
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+/// The bytes a request signature is made over
+pub fn signed_message(
+    op: Operation,
+    key: &str,
+    content_hash: Option<&[u8; 32]>,
+    seq: u64,
+    timestamp: u64,
+    nonce: &[u8; 16],
+) -> Vec<u8> {
+    let content_hash = content_hash.map(hex::encode).unwrap_or_else(|| "-".to_string());
+    format!(
+        "request:{}:{}:{}:{}:{}:{}",
+        op.as_str(),
+        timestamp,
+        hex::encode(nonce),
+        seq,
+        content_hash,
+        key
+    )
+    .into_bytes()
+}
+
+/// Hash of the content a request signs
+pub fn content_hash(content: &[u8]) -> [u8; 32] {
+    *blake3::hash(content).as_bytes()
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,293 @@
+use crate::api::{self, ApiRequest, ApiResponse};
+use crate::keys::RecordKey;
+use crate::node::auth::{Permissions, Role, Subject};
+use crate::node::Node;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use futures::channel::{mpsc, oneshot};
+use futures::future;
+
+/// A client request handed to the main loop, with where to send the answer
+pub type ApiCall = (ApiRequest, oneshot::Sender<ApiResponse>);
+
+/// Accept `kv-client` connections on `addr` and pass their requests to the main loop,
+/// which owns the node (see `handle`)
+pub async fn serve(addr: String, calls: mpsc::UnboundedSender<ApiCall>) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen for clients on {}: {}", addr, e);
+            return;
+        }
+    };
+    println!("Listening for clients on {}", addr);
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                let calls = calls.clone();
+                task::spawn(async move {
+                    if let Err(e) = answer(stream, calls).await {
+                        eprintln!("Client connection failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Failed to accept client connection: {}", e),
+        }
+    }
+}
+
+// Read one request from a client, wait for the main loop to carry it out and send back the answer
+async fn answer(mut stream: TcpStream, calls: mpsc::UnboundedSender<ApiCall>) -> Result<(), String> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await.map_err(|e| e.to_string())?;
+    let len = api::frame_len(header).ok_or("Request too large")?;
+    // The header is not authenticated: memory grows with the bytes that actually arrive,
+    // not with the length it announces
+    let mut body = Vec::new();
+    (&mut stream).take(len as u64).read_to_end(&mut body).await.map_err(|e| e.to_string())?;
+    if body.len() < len {
+        return Err("Request cut short".to_string());
+    }
+
+    let response = match api::decode_frame::<ApiRequest>(&body) {
+        Some(request) => {
+            let (respond, response) = oneshot::channel();
+            calls.unbounded_send((request, respond)).map_err(|_| "Node is shutting down")?;
+            response
+                .await
+                .unwrap_or_else(|_| ApiResponse::Error("Node is shutting down".to_string()))
+        }
+        None => ApiResponse::Error("Malformed request".to_string()),
+    };
+    stream
+        .write_all(&api::encode_frame(&response)?)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Carry out a client request on the node. The node logs why a request failed, the
+/// client is only told that it did.
+///
+/// A request whose signer or key this node knows too little about is put off: the user,
+/// ownership and permission records it is missing are looked up on the network first, and
+/// the request is handed back to the main loop through `calls` once the lookups are done.
+pub fn handle(node: &mut Node, request: ApiRequest, respond: oneshot::Sender<ApiResponse>, calls: &mpsc::UnboundedSender<ApiCall>) {
+    if let Some((public_key, key)) = signer(&request) {
+        let lookups = node.look_up_access_records(public_key, key);
+        if !lookups.is_empty() {
+            let calls = calls.clone();
+            task::spawn(async move {
+                future::join_all(lookups).await;
+                let _ = calls.unbounded_send((request, respond));
+            });
+            return;
+        }
+    }
+
+    let response = match request {
+        ApiRequest::Register { username, public_key, signature, admin } => {
+            match node.register_user(&username, &public_key, &signature, admin) {
+                Ok(()) if admin => ApiResponse::Done(format!("Registered admin user: {}", username)),
+                Ok(()) => ApiResponse::Done(format!("Registered user: {}", username)),
+                Err(e) => ApiResponse::Error(format!("Registration failed: {}", e)),
+            }
+        }
+        ApiRequest::Put { key, value, public_key, signature } => {
+            outcome(node.put(key, value, public_key, signature), "Successfully stored key-value pair", "Operation failed")
+        }
+        ApiRequest::Get { key, public_key, signature } => {
+            if !node.get(key.clone(), public_key, signature) {
+                ApiResponse::Error("Retrieval failed, see the node's log".to_string())
+            } else {
+                // The query only completes once the main loop runs again
+                let value = node.wait_for_record(&RecordKey::Value(key));
+                reply_with_record(value, respond, ApiResponse::Value);
+                return;
+            }
+        }
+        ApiRequest::Recipients { key, public_key, signature } => match node.file_recipients(&key, &public_key, &signature) {
+            Ok(recipients) => ApiResponse::Keys(recipients),
+            Err(e) => ApiResponse::Error(format!("Failed to list recipients: {}", e)),
+        },
+        ApiRequest::PutFile { key, manifest, chunks, public_key, signature } => outcome(
+            node.put_file_content(key, manifest, chunks, public_key, signature),
+            "Successfully stored file",
+            "File storage operation failed",
+        ),
+        ApiRequest::GetFileManifest { key, public_key, signature } => {
+            match node.get_file_manifest(&key, &public_key, &signature) {
+                Ok(manifest) => {
+                    reply_with_record(manifest, respond, ApiResponse::Value);
+                    return;
+                }
+                Err(e) => ApiResponse::Error(format!("File retrieval failed: {}", e)),
+            }
+        }
+        ApiRequest::GetChunk { hash, shard, attempt } => {
+            let chunk_key = if shard { RecordKey::Shard(hash) } else { RecordKey::Chunk(hash) };
+            reply_with_record(node.get_chunk(chunk_key, attempt), respond, ApiResponse::Value);
+            return;
+        }
+        ApiRequest::Sequence { key, file } => {
+            let record_key = if file { RecordKey::Manifest(key) } else { RecordKey::Value(key) };
+            let seq = node.write_sequence(&record_key);
+            task::spawn(async move {
+                let response = seq
+                    .await
+                    .map_or_else(|_| ApiResponse::Error("Node is shutting down".to_string()), ApiResponse::Sequence);
+                let _ = respond.send(response);
+            });
+            return;
+        }
+        ApiRequest::Delete { key, public_key, signature } => {
+            outcome(node.delete(key.clone(), public_key, signature), &format!("Deleted '{}'", key), "Delete failed")
+        }
+        ApiRequest::Grant { key, subject, permissions, public_key, signature } => {
+            match (Subject::parse(&subject), Permissions::parse(&permissions)) {
+                (Some(subject), Some(permissions)) => {
+                    match node.grant_permission(&key, &subject, permissions, public_key, signature) {
+                        Ok(_) => ApiResponse::Done(format!("Granted {} on key: {}", permissions, key)),
+                        Err(e) => ApiResponse::Error(format!("Failed to grant permission: {}", e)),
+                    }
+                }
+                _ => ApiResponse::Error("Invalid subject or permissions".to_string()),
+            }
+        }
+        ApiRequest::Revoke { key, subject, public_key, signature } => match Subject::parse(&subject) {
+            Some(subject) => match node.revoke_permission(&key, &subject, public_key, signature) {
+                Ok(true) => ApiResponse::Done(format!("Permissions revoked for key: {}", key)),
+                Ok(false) => ApiResponse::Done(format!("{} had no permissions on key: {}", subject, key)),
+                Err(e) => ApiResponse::Error(format!("Failed to revoke permissions: {}", e)),
+            },
+            None => ApiResponse::Error("Invalid subject, expected a public key or group:<name>".to_string()),
+        },
+        ApiRequest::Acl { key, public_key, signature } => match node.key_acl(&key, &public_key, &signature) {
+            Ok(permissions) => {
+                let owner = match node.user_manager.owner(&key) {
+                    Some(owner) => format!("owner {}", hex::encode(owner)),
+                    None => "no owner".to_string(),
+                };
+                let entries = permissions
+                    .into_iter()
+                    .map(|(subject, permissions)| format!("{}: {}", subject, permissions));
+                ApiResponse::Lines(std::iter::once(owner).chain(entries).collect())
+            }
+            Err(e) => ApiResponse::Error(format!("Failed to list permissions: {}", e)),
+        },
+        ApiRequest::UserAcl { user, public_key, signature } => match node.user_acl(&user, &public_key, &signature) {
+            Ok(permissions) if permissions.is_empty() => {
+                ApiResponse::Done(format!("No permissions for user {}", hex::encode(&user)))
+            }
+            Ok(permissions) => ApiResponse::Lines(
+                permissions
+                    .into_iter()
+                    .map(|(key, permissions)| format!("{}: {}", key, permissions))
+                    .collect(),
+            ),
+            Err(e) => ApiResponse::Error(format!("Failed to list permissions: {}", e)),
+        },
+        ApiRequest::SetRole { user, role, public_key, signature } => match Role::parse(&role) {
+            Some(role) => match node.set_role(&user, role, public_key, signature) {
+                Ok(_) => ApiResponse::Done(format!("User {} is now {}", hex::encode(&user), role.as_str())),
+                Err(e) => ApiResponse::Error(format!("Failed to change role: {}", e)),
+            },
+            None => ApiResponse::Error("Unknown role, expected admin, writer or reader".to_string()),
+        },
+        ApiRequest::GroupMember { group, user, member, public_key, signature } => {
+            match node.set_group_member(&group, &user, member, public_key, signature) {
+                Ok(_) if member => ApiResponse::Done(format!("Added {} to group {}", hex::encode(&user), group)),
+                Ok(_) => ApiResponse::Done(format!("Removed {} from group {}", hex::encode(&user), group)),
+                Err(e) => ApiResponse::Error(format!("Failed to change group: {}", e)),
+            }
+        }
+        ApiRequest::RotateKey { public_key, signature, new_public_key, new_signature } => {
+            match node.rotate_key(public_key, signature, new_public_key.clone(), new_signature) {
+                Ok(()) => ApiResponse::Done(format!("Key replaced, the new public key is {}", hex::encode(&new_public_key))),
+                Err(e) => ApiResponse::Error(format!("Failed to replace key: {}", e)),
+            }
+        }
+        ApiRequest::Audit { key, public_key, signature } => {
+            match node.audit_lines(key.as_deref(), &public_key, &signature) {
+                Ok(lines) => ApiResponse::Lines(lines),
+                Err(e) => ApiResponse::Error(format!("Failed to read the audit log: {}", e)),
+            }
+        }
+        ApiRequest::Users { username } => ApiResponse::Lines(user_lines(node, username.as_deref())),
+        ApiRequest::Downloads => ApiResponse::Lines(node.swarm.behaviour().download_status()),
+        ApiRequest::Peers => ApiResponse::Lines(node.swarm.behaviour().peers.status()),
+    };
+    // The client may have disconnected in the meantime
+    let _ = respond.send(response);
+}
+
+// Who signed a request and the key it is about, None for requests that are not signed
+fn signer(request: &ApiRequest) -> Option<(&[u8], Option<&str>)> {
+    match request {
+        ApiRequest::Put { key, public_key, .. }
+        | ApiRequest::Get { key, public_key, .. }
+        | ApiRequest::Recipients { key, public_key, .. }
+        | ApiRequest::PutFile { key, public_key, .. }
+        | ApiRequest::GetFileManifest { key, public_key, .. }
+        | ApiRequest::Delete { key, public_key, .. }
+        | ApiRequest::Grant { key, public_key, .. }
+        | ApiRequest::Revoke { key, public_key, .. }
+        | ApiRequest::Acl { key, public_key, .. } => Some((public_key, Some(key))),
+        ApiRequest::UserAcl { public_key, .. }
+        | ApiRequest::SetRole { public_key, .. }
+        | ApiRequest::GroupMember { public_key, .. }
+        | ApiRequest::RotateKey { public_key, .. }
+        | ApiRequest::Audit { public_key, .. } => Some((public_key, None)),
+        ApiRequest::Register { .. }
+        | ApiRequest::GetChunk { .. }
+        | ApiRequest::Sequence { .. }
+        | ApiRequest::Users { .. }
+        | ApiRequest::Downloads
+        | ApiRequest::Peers => None,
+    }
+}
+
+/// Every known user, or only `username`, with each of their keys and its role. Keys that
+/// were replaced by a newer one are listed as replaced.
+pub fn user_lines(node: &Node, username: Option<&str>) -> Vec<String> {
+    let usernames = match username {
+        Some(username) => vec![username.to_string()],
+        None => node.user_manager.usernames(),
+    };
+
+    let mut lines = Vec::new();
+    for username in usernames {
+        for public_key in node.user_manager.keys_of(&username) {
+            let role = node.user_manager.role(&public_key).map_or("replaced", |role| role.as_str());
+            lines.push(format!("{} {} {}", username, hex::encode(&public_key), role));
+        }
+    }
+    lines
+}
+
+// Answer for node methods that only report success or failure
+fn outcome(succeeded: bool, done: &str, failed: &str) -> ApiResponse {
+    if succeeded {
+        ApiResponse::Done(done.to_string())
+    } else {
+        ApiResponse::Error(format!("{}, see the node's log", failed))
+    }
+}
+
+// Answer the client once the record it asked for has been retrieved, without holding up the main loop
+fn reply_with_record(
+    record: oneshot::Receiver<Result<Vec<u8>, String>>,
+    respond: oneshot::Sender<ApiResponse>,
+    to_response: fn(Vec<u8>) -> ApiResponse,
+) {
+    task::spawn(async move {
+        let response = match record.await {
+            Ok(Ok(value)) => to_response(value),
+            Ok(Err(e)) => ApiResponse::Error(format!("Retrieval failed: {}", e)),
+            Err(_) => ApiResponse::Error("Node is shutting down".to_string()),
+        };
+        let _ = respond.send(response);
+    });
+}